@@ -1,5 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fcs::resolve::SegmentContext;
 use fcs::run;
+use fcs::syntax::{self, Syntax};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::path;
@@ -28,9 +30,7 @@ pub fn bench_get_new_name(c: &mut Criterion) {
                 black_box(&dest),
                 black_box(&codes),
                 black_box(None),
-                (black_box('.'), black_box(1)),
-                (black_box('{'), black_box('}')),
-                black_box(','),
+                black_box(&Syntax::default()),
             )
         })
     });
@@ -51,22 +51,46 @@ pub fn bench_expand(c: &mut Criterion) {
             run::expand(
                 black_box(&name),
                 black_box(&codes),
-                black_box('{'),
-                black_box('}'),
-                black_box(None),
+                black_box(&SegmentContext::default()),
+                black_box(&Syntax::default()),
                 black_box(&mut vec![]),
-                black_box(','),
             )
         })
     });
 }
 
-pub fn bench_brackets(c: &mut Criterion) {
+pub fn bench_parse(c: &mut Criterion) {
     let string = "{{}";
 
-    c.bench_function("brackets", |b| {
+    c.bench_function("parse", |b| {
         b.iter(|| {
-            run::find_first_valid_opening_bracket(black_box(string), black_box('{'), black_box('}'))
+            let tokens = syntax::tokenize(black_box(string), black_box(&Syntax::default()));
+            syntax::parse(black_box(string), &tokens)
+        })
+    });
+}
+
+// Long names, with variables nested deeply in each part
+pub fn bench_nested(c: &mut Criterion) {
+    let codes: HashMap<String, String> = [("a", "Alpha"), ("Alpha", "a")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let dest = path::PathBuf::from("/home/default/Documents");
+
+    let depth = 40;
+    let part = format!("{}a{}", "{".repeat(depth), "}".repeat(depth));
+    let name = format!("{}.file.txt", [part.as_str(); 50].join("."));
+
+    c.bench_function("nested", |b| {
+        b.iter(|| {
+            run::get_new_name(
+                black_box(&name),
+                black_box(&dest),
+                black_box(&codes),
+                black_box(None),
+                black_box(&Syntax::default()),
+            )
         })
     });
 }
@@ -76,16 +100,14 @@ fn get_new_name_without_errors(
     dest: &path::Path,
     codes: &HashMap<String, String>,
     timestamp: Option<time::SystemTime>,
-    separator: (char, usize),
-    var: (char, char),
-    last_token: char,
+    syntax: &Syntax,
 ) {
-    run::get_new_name(name, dest, codes, timestamp, separator, var, last_token).ok();
+    run::get_new_name(name, dest, codes, timestamp, syntax).ok();
 }
 
 pub fn bench_all(c: &mut Criterion) {
     let destination = path::PathBuf::from("/home/some_user/Documents/");
-    let codes: HashMap<String, String> = vec![
+    let codes: HashMap<String, String> = [
         ("pc", "Physique-Chimie"),
         ("gr", "Grec"),
         ("fr", "Français"),
@@ -128,9 +150,7 @@ pub fn bench_all(c: &mut Criterion) {
                         black_box(&destination),
                         black_box(&codes),
                         black_box(Some(time::SystemTime::now())),
-                        black_box(('.', 1)),
-                        black_box(('{', '}')),
-                        black_box(','),
+                        black_box(&Syntax::default()),
                     )
                 })
                 .collect();
@@ -142,7 +162,8 @@ criterion_group!(
     benches,
     bench_get_new_name,
     bench_expand,
-    bench_brackets,
+    bench_parse,
     bench_all,
+    bench_nested,
 );
 criterion_main!(benches);
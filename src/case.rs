@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::Path;
+
+use crate::conf::lib::DestCase;
+use crate::run::NewName;
+use crate::transform::Transform;
+use crate::unicode::Normalization;
+
+pub fn apply(policy: DestCase, name: &str) -> String {
+    match policy {
+        DestCase::Preserve => name.to_owned(),
+        DestCase::Lower => name.to_lowercase(),
+        DestCase::Title => Transform::Title.apply(name),
+    }
+}
+
+// The directory in `parent` named like `name`, whatever its case if
+// `fold_case`, and how its accented letters are written unless they're kept
+// as they are. The one named exactly like it wins
+fn existing(
+    parent: &Path,
+    name: &str,
+    fold_case: bool,
+    normalization: Normalization,
+) -> Option<String> {
+    // Both forms are compared the same way, whichever one is written
+    let compared = |name: &str| {
+        let name = if fold_case {
+            name.to_lowercase()
+        } else {
+            name.to_owned()
+        };
+        match normalization {
+            Normalization::None => name,
+            _ => Normalization::Nfc.apply(&name).into_owned(),
+        }
+    };
+    let lowercase = compared(name);
+
+    let mut found: Vec<String> = fs::read_dir(parent)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|entry| compared(entry) == lowercase)
+        .collect();
+
+    if found.iter().any(|entry| entry == name) {
+        return Some(name.to_owned());
+    }
+    found.sort();
+    found.into_iter().next()
+}
+
+// Writes the directories the file goes to following the policy. Unless it
+// preserves the case, the existing ones which only differ in case are reused,
+// so that there's never both `Mathematics` and `mathematics`
+pub fn normalize(
+    new_name: NewName,
+    dest: &Path,
+    policy: DestCase,
+    normalization: Normalization,
+) -> NewName {
+    let relative = match new_name.dir.strip_prefix(dest) {
+        Ok(relative) => relative.to_owned(),
+        Err(_) => return new_name,
+    };
+
+    let mut dir = dest.to_owned();
+    // Each directory, and how it's written now
+    let mut renamed: Vec<(String, String)> = vec![];
+    let mut exists = true;
+
+    for component in relative.iter() {
+        let name = match component.to_str() {
+            Some(name) => name,
+            None => {
+                dir.push(component);
+                exists = false;
+                continue;
+            }
+        };
+
+        let mut cased = apply(policy, name);
+        if exists {
+            match existing(&dir, &cased, policy != DestCase::Preserve, normalization) {
+                Some(found) => cased = found,
+                None => exists = false,
+            }
+        }
+
+        dir.push(&cased);
+        renamed.push((name.to_owned(), cased));
+    }
+
+    // Segments are found in order among the directories, which also hold the
+    // year and month. They can be several directories, or none
+    let mut next = 0;
+    let segments = new_name
+        .segments
+        .iter()
+        .map(|segment| {
+            let components: Vec<String> = segment
+                .split('/')
+                .map(|component| {
+                    match renamed[next..]
+                        .iter()
+                        .position(|(name, _)| name == component)
+                    {
+                        Some(offset) => {
+                            next += offset + 1;
+                            renamed[next - 1].1.clone()
+                        }
+                        None => component.to_owned(),
+                    }
+                })
+                .collect();
+            components.join("/")
+        })
+        .collect();
+
+    let file = match new_name.file.file_name() {
+        Some(file_name) => dir.join(file_name),
+        None => new_name.file,
+    };
+
+    NewName {
+        file,
+        dir,
+        codes: new_name.codes,
+        segments,
+    }
+}
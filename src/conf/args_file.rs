@@ -1,9 +1,118 @@
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 
 use super::cli;
 use super::lib as conf;
+use super::references;
+use crate::resolve::Code;
+
+// Keeps the valid codes, and the valid ones of their namespaces. They're
+// lowercased when codes are matched whatever their case. Aliases are added
+// as codes meaning the same
+fn valid_codes(
+    codes: &HashMap<String, Code>,
+    is_valid: &dyn Fn(&str, &str) -> bool,
+    lowercase: bool,
+) -> HashMap<String, Code> {
+    let mut keys: Vec<&String> = codes.keys().collect();
+    keys.sort();
+
+    let mut valid: HashMap<String, Code> = HashMap::new();
+    for key in keys {
+        let code = match &codes[key] {
+            code if !is_valid(key, code.meaning()) => {
+                log::warn!(
+                    "Shortcut `{:#}={:#}` isn't valid ! Not using it",
+                    key,
+                    code.meaning()
+                );
+                continue;
+            }
+            Code::Meaning(meaning) => Code::Meaning(meaning.to_owned()),
+            Code::Entry {
+                name,
+                aliases,
+                codes,
+            } => Code::Entry {
+                name: name.to_owned(),
+                aliases: aliases.iter().map(|alias| case(alias, lowercase)).collect(),
+                codes: valid_codes(codes, is_valid, lowercase),
+            },
+        };
+
+        let key = case(key, lowercase);
+        match valid.get(&key) {
+            Some(other) if other.meaning() != code.meaning() => log::warn!(
+                "Shortcut `{:#}={:#}` only differs in case from another one, meaning `{:#}` ! Not using it",
+                key,
+                code.meaning(),
+                other.meaning()
+            ),
+            Some(_) => (),
+            None => {
+                valid.insert(key, code);
+            }
+        }
+    }
+
+    let mut keys: Vec<String> = valid.keys().cloned().collect();
+    keys.sort();
+
+    for key in keys {
+        let mut code = valid[&key].clone();
+        if let Code::Entry { name, aliases, .. } = &mut code {
+            // Aliases which can't be used aren't shown either
+            aliases.retain(|alias| {
+                if !is_valid(alias, name) {
+                    log::warn!(
+                        "Alias `{:#}` of shortcut `{:#}` isn't valid ! Not using it",
+                        alias,
+                        key
+                    );
+                    false
+                } else if valid
+                    .get(alias)
+                    .is_some_and(|other| other.meaning() != name.as_str())
+                {
+                    log::warn!(
+                        "Alias `{:#}` of shortcut `{:#}` is already a shortcut ! Not using it",
+                        alias,
+                        key
+                    );
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        for alias in code.aliases() {
+            valid.insert(alias.to_owned(), code.clone());
+        }
+        valid.insert(key, code);
+    }
+
+    valid
+}
+
+// Meanings can be several directories, like `Science/Physics`, or nothing
+// to leave the part out, but can't go outside of the destination
+fn is_valid_meaning(meaning: &str) -> bool {
+    meaning.is_empty()
+        || meaning.split('/').all(|component| {
+            let mut components = Path::new(component).components();
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+        })
+}
+
+fn case(code: &str, lowercase: bool) -> String {
+    if lowercase {
+        code.to_lowercase()
+    } else {
+        code.to_owned()
+    }
+}
 
 impl conf::Config {
     // Get config from CLI args and config file
@@ -23,36 +132,29 @@ impl conf::Config {
     // up that it is unusable, and if the program should exit
     pub fn clean(&mut self, mutates: bool) -> bool {
         let mut fatal = false;
-        let mut true_fatal = false;
 
         let dest;
         match shellexpand::full(self.dest.to_str().unwrap()) {
             Ok(result) => dest = PathBuf::from_str(&result).unwrap(),
             Err(e) => {
                 if mutates {
-                    log::error!(
-                        "Error while expanding destination : {}. Exiting",
-                        e.to_string()
-                    );
+                    log::error!("Error while expanding destination : {}. Exiting", e);
                 } else {
-                    log::warn!("Error while expanding destination : {}", e.to_string());
+                    log::warn!("Error while expanding destination : {}", e);
                 }
                 fatal = true;
 
                 dest = self.dest.clone();
             }
         }
-        let dirs: HashSet<_> = self
-            .dirs
-            .iter()
-            .map(|dir| match shellexpand::full(dir.to_str().unwrap()) {
-                Ok(result) => PathBuf::from_str(&result).unwrap(),
-                Err(e) => {
-                    log::warn!("Error while expanding dirs : {}", e.to_string());
-                    dir.to_owned()
-                }
-            })
-            .collect();
+        let expand_dir = |dir: &PathBuf| match shellexpand::full(dir.to_str().unwrap()) {
+            Ok(result) => PathBuf::from_str(&result).unwrap(),
+            Err(e) => {
+                log::warn!("Error while expanding dirs : {}", e);
+                dir.to_owned()
+            }
+        };
+        let dirs: HashSet<_> = self.dirs.iter().map(expand_dir).collect();
 
         let existing_dirs: HashSet<_> = dirs
             .iter()
@@ -68,6 +170,22 @@ impl conf::Config {
             );
         }
 
+        let dir_options: HashMap<_, _> = self
+            .dir_options
+            .iter()
+            .map(|(dir, options)| (expand_dir(dir), options.clone()))
+            .collect();
+
+        for dir in dir_options
+            .keys()
+            .filter(|dir| !existing_dirs.contains(*dir))
+        {
+            log::warn!(
+                "Options are set for `{:#?}`, which isn't a watching directory. Not using them",
+                dir
+            );
+        }
+
         if existing_dirs.is_empty() {
             if mutates {
                 log::error!("No directories set up, or none of them exist ! Exiting");
@@ -92,74 +210,132 @@ impl conf::Config {
             fatal = true;
         }
 
-        let valid_codes: HashMap<String, String> = self
-            .codes
-            .iter()
-            .filter(|entry| {
-                !vec![".", ".."].contains(&entry.1.as_str())
-                    && entry.0.matches('.').count() < 1
-                    && !entry.0.is_empty()
-                    && entry.0.matches('/').count() < 1
-                    && !entry.1.is_empty()
-                    && entry.1.matches('/').count() < 1
-                    && entry.0.matches(self.begin_var).count()
-                        + entry.0.matches(self.end_var).count()
-                        + entry.0.matches(self.separator).count()
-                        + entry.0.matches(self.last_token).count()
-                        < 1
-            })
-            .map(|entry| (entry.0.to_owned(), entry.1.to_owned()))
-            .collect();
+        let true_fatal = self.clean_files(mutates);
 
-        let valid_codes_keys: HashSet<String> =
-            valid_codes.iter().map(|entry| entry.0.to_owned()).collect();
-        let codes_keys: HashSet<String> =
-            self.codes.iter().map(|entry| entry.0.to_owned()).collect();
+        if mutates {
+            self.dest = dest;
+            self.dirs = existing_dirs;
+            self.dir_options = dir_options;
+        }
 
-        let invalid_codes_keys = codes_keys.difference(&valid_codes_keys);
+        log::debug!("Here's the config : {:#?}", self);
 
-        for key in invalid_codes_keys {
-            log::warn!(
-                "Shortcut `{:#}={:#}` isn't valid ! Not using it",
-                key,
-                self.codes[key]
-            );
-        }
+        true_fatal || (fatal && mutates)
+    }
+
+    // Checks the settings about files, which directory-local configuration
+    // files can set too. Returns whether they make the config unusable
+    pub fn clean_files(&mut self, mutates: bool) -> bool {
+        let mut true_fatal = false;
+
+        let tokens = [
+            ("begin variable token", &self.begin_var),
+            ("end variable token", &self.end_var),
+            ("separator token", &self.separator),
+            ("last token", &self.last_token),
+        ];
+
+        let is_valid = |key: &str, meaning: &str| {
+            is_valid_meaning(meaning)
+                && key.matches('.').count() < 1
+                && !key.is_empty()
+                && key.matches('/').count() < 1
+                && !tokens.iter().any(|(_, token)| token.is_in(key))
+                && key.matches(self.escape).count() < 1
+        };
+        let codes = self.unicode_normalization.codes(&self.codes);
+        let expanded = references::expand(
+            &valid_codes(&codes, &is_valid, self.case_insensitive_codes),
+            &self.syntax(),
+            self.case_insensitive_codes,
+        );
+        // References could have made meanings invalid, like `..` out of `.{omitted}`
+        let valid_codes = valid_codes(&expanded, &is_valid, self.case_insensitive_codes);
 
         if valid_codes.is_empty() {
             log::error!("No shortcut set up, or none of them are valid ! Exiting");
             true_fatal = true;
         }
 
-        if self.begin_var == self.end_var {
-            log::error!(
-                "The 'begin variable token' ({}) is identical to the 'end variable token' ({})",
-                self.begin_var,
-                self.end_var
-            );
-            true_fatal = true;
-        }
+        for (index, (name, token)) in tokens.iter().enumerate() {
+            if token.alternatives().is_empty() || token.alternatives().iter().any(String::is_empty)
+            {
+                log::error!("The '{}' ({}) can't be empty", name, token);
+                true_fatal = true;
+            }
 
-        if vec![self.begin_var, self.end_var].contains(&self.separator) {
-            log::error!("The 'separator token' ({}) is identical to either the 'begin variable token' ({}) or the 'end variable token' ({})",
-        self.separator, self.begin_var, self.end_var);
-            true_fatal = true;
+            if token
+                .alternatives()
+                .iter()
+                .any(|alternative| alternative.starts_with(self.escape))
+            {
+                log::error!(
+                    "The '{}' ({}) starts with the 'escape token' ({})",
+                    name,
+                    token,
+                    self.escape
+                );
+                true_fatal = true;
+            }
+
+            for (other_name, other) in &tokens[index + 1..] {
+                if token
+                    .alternatives()
+                    .iter()
+                    .any(|alternative| other.alternatives().contains(alternative))
+                {
+                    log::error!(
+                        "The '{}' ({}) is identical to the '{}' ({})",
+                        name,
+                        token,
+                        other_name,
+                        other
+                    );
+                    true_fatal = true;
+                }
+            }
         }
 
-        if vec![self.begin_var, self.end_var, self.separator].contains(&self.last_token) {
-            log::error!("The 'last token' ({}) is identical to either the 'begin variable token' ({}), the 'end variable token' ({}), or the 'separator token' ({})",
-        self.last_token, self.begin_var, self.end_var, self.last_token);
-            true_fatal = true;
+        // Extensions are compared without their leading dot, ignoring case
+        let extensions: Vec<String> = self
+            .extensions
+            .iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .filter(|extension| {
+                if extension.is_empty() {
+                    log::warn!("An empty extension is set. Not using it");
+                }
+                !extension.is_empty()
+            })
+            .collect();
+
+        // MIME types are compared ignoring case, and routes are like meanings
+        let mime_routes: HashMap<String, PathBuf> = self
+            .mime_routes
+            .iter()
+            .filter_map(|(mime, route)| {
+                let valid = (mime == "*" || mime.matches('/').count() == 1)
+                    && route.to_str().is_some_and(is_valid_meaning);
+                if !valid {
+                    log::warn!("Route `{:#}={:#?}` isn't valid ! Not using it", mime, route);
+                }
+                valid.then(|| (mime.to_lowercase(), route.to_owned()))
+            })
+            .collect();
+
+        if let Some(source) = &self.script {
+            if let Err(e) = crate::script::Script::compile(source, self.script_timeout) {
+                log::error!("The script can't be compiled : {}", e);
+                true_fatal = true;
+            }
         }
 
         if mutates {
-            self.dest = dest;
-            self.dirs = existing_dirs;
             self.codes = valid_codes;
+            self.extensions = extensions;
+            self.mime_routes = mime_routes;
         }
 
-        log::debug!("Here's the config : {:#?}", self);
-
-        true_fatal || (fatal && mutates)
+        true_fatal
     }
 }
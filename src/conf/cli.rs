@@ -1,3 +1,4 @@
+use chrono::NaiveDate;
 use dirs_next::config_dir;
 use structopt::clap::Shell;
 use structopt::StructOpt;
@@ -9,6 +10,10 @@ use std::process::exit;
 use std::{path, path::PathBuf};
 
 use super::lib;
+use crate::dedupe::Duplicates;
+use crate::resolve::Code;
+use crate::sanitize::Sanitize;
+use crate::unicode::{Ascii, Normalization};
 
 fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn Error>>
 where
@@ -30,7 +35,6 @@ where
     author = "Elie Le Vaillant <elielevaillant2007@gmail.com>",
     setting = structopt::clap::AppSettings::ColoredHelp
 )]
-
 pub struct Cli {
     /// Sets the configurationg file
     #[structopt(short = "-C", long, value_name = "file")]
@@ -85,27 +89,146 @@ pub struct Cli {
     generate_config: bool,
 
     /// Set the separator to separate each filename part ('.' by default)
-    #[structopt(short = "-p", long, value_name = "char")]
-    separator: Option<char>,
+    ///
+    /// Can be given several times, each of them being a valid separator
+    #[structopt(short = "-p", long, value_name = "string", number_of_values = 1)]
+    separator: Option<Vec<String>>,
 
-    /// Set the number of characters that are the separator in the filename
+    /// Set the number of separators that are part of the file's own name
+    /// (1 by default)
     #[structopt(short, long, value_name = "number")]
     filename_separators: Option<usize>,
 
-    /// Set the character to detect a variable 'lookup'
-    /// ('{' by default)
-    #[structopt(short, long, value_name = "char")]
-    begin_var: Option<char>,
-
-    /// Set the character to detect the end of a variable 'lookup'
-    /// ('}' by default)
-    #[structopt(short, long, value_name = "char")]
-    end_var: Option<char>,
+    /// Find the file's own name from its extension, unless the number of
+    /// separators in it is set
+    #[structopt(long)]
+    detect_extensions: bool,
+
+    /// Add an extension to recognize, like 'tar.gz'
+    /// (can be given several times)
+    #[structopt(long = "extension", value_name = "extension", number_of_values = 1)]
+    extensions: Option<Vec<String>>,
+
+    /// Set the string to detect a variable 'lookup'
+    /// ('{' by default, can be given several times)
+    #[structopt(short, long, value_name = "string", number_of_values = 1)]
+    begin_var: Option<Vec<String>>,
+
+    /// Set the string to detect the end of a variable 'lookup'
+    /// ('}' by default, can be given several times)
+    #[structopt(short, long, value_name = "string", number_of_values = 1)]
+    end_var: Option<Vec<String>>,
+
+    /// Set the string to expand to the last code
+    /// (',' by default, can be given several times)
+    #[structopt(short, long, value_name = "string", number_of_values = 1)]
+    last_token: Option<Vec<String>>,
+
+    /// Set the character making the next one literal, even if it's a separator or a token
+    /// ('\\' by default)
+    #[structopt(long, value_name = "char")]
+    escape: Option<char>,
+
+    /// Sets the time in ms a file's size and modification time must stay unchanged before moving it
+    /// (0, ie disabled, by default)
+    #[structopt(long, value_name = "milliseconds")]
+    settle: Option<usize>,
+
+    /// Sets the minimum time in ms since a file's last modification before moving it
+    #[structopt(long, value_name = "milliseconds")]
+    min_age: Option<usize>,
+
+    /// Doesn't move files that are opened by a process (Linux only)
+    #[structopt(long)]
+    check_open: bool,
+
+    /// Sets how deep to look for files in the watching directories
+    /// (0 means only the top level, no limit by default)
+    #[structopt(long, value_name = "number")]
+    max_depth: Option<usize>,
+
+    /// Sets which symlinks are followed when looking for files ('files' by default)
+    #[structopt(long, value_name = "policy", possible_values(&["skip", "files", "all"]))]
+    follow_symlinks: Option<lib::SymlinkPolicy>,
+
+    /// Moves the files symlinks point to, instead of the symlinks themselves
+    #[structopt(long)]
+    resolve_symlinks: bool,
+
+    /// Removes the directories that were emptied by moving files out of them
+    #[structopt(long)]
+    prune_empty_dirs: bool,
+
+    /// Sets the time in ms a directory must stay empty before being removed
+    /// (10000 by default)
+    #[structopt(long, value_name = "milliseconds")]
+    prune_grace: Option<usize>,
+
+    /// Sets the time in ms a script function may run for before being stopped
+    /// (100 by default)
+    #[structopt(long, value_name = "milliseconds")]
+    script_timeout: Option<usize>,
+
+    /// Matches codes whatever their case
+    #[structopt(long)]
+    case_insensitive_codes: bool,
+
+    /// Sets how destination directories are written ('preserve' by default)
+    #[structopt(long, value_name = "policy", possible_values(&["preserve", "lower", "title"]))]
+    dest_case: Option<lib::DestCase>,
+
+    /// Sets which filesystems directory and file names must be valid on ('posix' by default)
+    #[structopt(long, value_name = "profile", possible_values(&["posix", "windows", "portable"]))]
+    sanitize: Option<Sanitize>,
+
+    /// Sets how accented letters are written, in file names, codes and destinations ('none' by default)
+    #[structopt(long, value_name = "form", possible_values(&["nfc", "nfd", "none"]))]
+    unicode_normalization: Option<Normalization>,
+
+    /// Writes destination directories and files with ASCII only ('none' by default)
+    #[structopt(long, value_name = "mode", possible_values(&["none", "transliterate", "slugify"]))]
+    ascii: Option<Ascii>,
+
+    /// Sets what's done with files identical to one already in the destination ('ignore' by default)
+    #[structopt(long, value_name = "policy", possible_values(&["ignore", "keep", "delete", "hardlink"]))]
+    duplicates: Option<Duplicates>,
+
+    /// Sets the SQLite database moves are recorded in, relative to the destination
+    #[structopt(long, value_name = "file")]
+    database: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
 
-    /// Set the character to expand to the last code
-    /// (',') by default
-    #[structopt(short, long, value_name = "char")]
-    last_token: Option<char>,
+// What to do instead of organizing files
+#[derive(Debug, Clone, StructOpt)]
+pub enum Command {
+    /// Lists the files of the destination which are identical
+    Dedupe,
+
+    /// Searches the files recorded in the database
+    Search {
+        /// Files having this code
+        #[structopt(long, value_name = "code")]
+        code: Option<String>,
+
+        /// Files having a part expanded to a text containing this one
+        #[structopt(long, value_name = "text")]
+        meaning: Option<String>,
+
+        /// Files whose original name contains this text
+        #[structopt(long, value_name = "text")]
+        name: Option<String>,
+
+        /// Files moved on this day or later (YYYY-MM-DD)
+        #[structopt(long, value_name = "date")]
+        since: Option<NaiveDate>,
+
+        /// Files moved on this day or earlier (YYYY-MM-DD)
+        #[structopt(long, value_name = "date")]
+        until: Option<NaiveDate>,
+    },
 }
 
 macro_rules! define_option {
@@ -140,7 +263,7 @@ macro_rules! define_bool {
 
 impl lib::Config {
     pub fn from_args(args: Cli) -> (Self, String, lib::DeclaredType) {
-        let mut declared: lib::DeclaredType = [false; 13];
+        let mut declared: lib::DeclaredType = [false; 36];
 
         if let Some(shell) = args.completion {
             let mut app = Cli::clap();
@@ -176,9 +299,23 @@ impl lib::Config {
             codes,
             separator,
             filename_separators,
+            extensions,
             begin_var,
             end_var,
-            last_token
+            last_token,
+            escape,
+            settle,
+            min_age,
+            max_depth,
+            follow_symlinks,
+            prune_grace,
+            script_timeout,
+            dest_case,
+            sanitize,
+            unicode_normalization,
+            ascii,
+            duplicates,
+            database
         );
 
         define_bool!(
@@ -188,7 +325,12 @@ impl lib::Config {
             // Bools to define
             once,
             timeinfo,
-            static_mode
+            static_mode,
+            check_open,
+            resolve_symlinks,
+            prune_empty_dirs,
+            detect_extensions,
+            case_insensitive_codes
         );
 
         let result = convert_types(build_result);
@@ -196,17 +338,16 @@ impl lib::Config {
         if args.generate_config {
             let mut result = result;
 
-            match result.clean(false) {
-                true => {
-                    log::error!("Configuration is unusable");
-                    exit(exitcode::DATAERR);
-                }
-                false => (),
+            if result.clean(false) {
+                log::error!("Configuration is unusable");
+                exit(exitcode::DATAERR);
             }
 
             let yaml_result = lib::ConfigSerDe {
                 separator: Some(result.separator),
-                filename_separators: Some(result.filename_separators),
+                filename_separators: result.filename_separators,
+                detect_extensions: Some(result.detect_extensions),
+                extensions: Some(result.extensions),
                 begin_var: Some(result.begin_var),
                 end_var: Some(result.end_var),
                 dest: Some(result.dest),
@@ -217,15 +358,33 @@ impl lib::Config {
                 sleep: Some(result.sleep),
                 codes: Some(result.codes),
                 last_token: Some(result.last_token),
+                escape: Some(result.escape),
+                settle: Some(result.settle),
+                min_age: Some(result.min_age),
+                check_open: Some(result.check_open),
+                max_depth: result.max_depth,
+                follow_symlinks: Some(result.follow_symlinks),
+                resolve_symlinks: Some(result.resolve_symlinks),
+                dir_options: Some(result.dir_options),
+                prune_empty_dirs: Some(result.prune_empty_dirs),
+                prune_grace: Some(result.prune_grace),
+                hooks: Some(result.hooks),
+                script: result.script,
+                script_timeout: Some(result.script_timeout),
+                case_insensitive_codes: Some(result.case_insensitive_codes),
+                dest_case: Some(result.dest_case),
+                sanitize: Some(result.sanitize),
+                unicode_normalization: Some(result.unicode_normalization),
+                ascii: Some(result.ascii),
+                mime_routes: Some(result.mime_routes),
+                duplicates: Some(result.duplicates),
+                database: result.database,
             };
 
             let deserialized = match serde_yaml::to_string(&yaml_result) {
                 Ok(res) => res,
                 Err(e) => {
-                    log::error!(
-                        "Failed somehow to parse configuration. Error : {}",
-                        e.to_string()
-                    );
+                    log::error!("Failed somehow to parse configuration. Error : {}", e);
                     exit(exitcode::DATAERR);
                 }
             };
@@ -238,11 +397,11 @@ impl lib::Config {
 }
 
 fn convert_types(build_result: lib::BuildConfig) -> lib::Config {
-    let codes: HashMap<String, String> = build_result
+    let codes: HashMap<String, Code> = build_result
         .codes
         .unwrap()
         .iter()
-        .map(|x| (x.0.to_owned(), x.1.to_owned()))
+        .map(|x| (x.0.to_owned(), x.1.as_str().into()))
         .collect();
     let dirs: HashSet<PathBuf> = build_result
         .dirs
@@ -253,15 +412,38 @@ fn convert_types(build_result: lib::BuildConfig) -> lib::Config {
 
     let dest = build_result.dest.unwrap();
     let sleep = build_result.sleep.unwrap();
-    let separator = build_result.separator.unwrap();
-    let filename_separators = build_result.filename_separators.unwrap();
-    let begin_var = build_result.begin_var.unwrap();
-    let end_var = build_result.end_var.unwrap();
-    let last_token = build_result.last_token.unwrap();
+    let separator = build_result.separator.unwrap().into();
+    let filename_separators = build_result.filename_separators;
+    let detect_extensions = build_result.detect_extensions;
+    let extensions = build_result.extensions.unwrap();
+    let begin_var = build_result.begin_var.unwrap().into();
+    let end_var = build_result.end_var.unwrap().into();
+    let last_token = build_result.last_token.unwrap().into();
+    let escape = build_result.escape.unwrap();
+    let settle = build_result.settle.unwrap();
+    let min_age = build_result.min_age.unwrap();
+    let max_depth = build_result.max_depth;
+    let follow_symlinks = build_result.follow_symlinks.unwrap();
+    let dir_options = build_result.dir_options.unwrap();
+    let prune_grace = build_result.prune_grace.unwrap();
+    let hooks = build_result.hooks.unwrap();
+    let script = build_result.script;
+    let script_timeout = build_result.script_timeout.unwrap();
+    let dest_case = build_result.dest_case.unwrap();
+    let sanitize = build_result.sanitize.unwrap();
+    let unicode_normalization = build_result.unicode_normalization.unwrap();
+    let ascii = build_result.ascii.unwrap();
+    let mime_routes = build_result.mime_routes.unwrap();
+    let duplicates = build_result.duplicates.unwrap();
+    let database = build_result.database;
 
     let once = build_result.once;
     let timeinfo = build_result.timeinfo;
     let static_mode = build_result.static_mode;
+    let check_open = build_result.check_open;
+    let resolve_symlinks = build_result.resolve_symlinks;
+    let prune_empty_dirs = build_result.prune_empty_dirs;
+    let case_insensitive_codes = build_result.case_insensitive_codes;
 
     lib::Config {
         codes,
@@ -273,8 +455,31 @@ fn convert_types(build_result: lib::BuildConfig) -> lib::Config {
         static_mode,
         separator,
         filename_separators,
+        detect_extensions,
+        extensions,
         begin_var,
         end_var,
         last_token,
+        escape,
+        settle,
+        min_age,
+        check_open,
+        max_depth,
+        follow_symlinks,
+        resolve_symlinks,
+        dir_options,
+        prune_empty_dirs,
+        prune_grace,
+        hooks,
+        script,
+        script_timeout,
+        case_insensitive_codes,
+        dest_case,
+        sanitize,
+        unicode_normalization,
+        ascii,
+        mime_routes,
+        duplicates,
+        database,
     }
 }
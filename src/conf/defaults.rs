@@ -2,7 +2,10 @@ use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use super::lib;
+use super::lib::{self, DestCase, SymlinkPolicy};
+use crate::dedupe::Duplicates;
+use crate::sanitize::Sanitize;
+use crate::unicode::{Ascii, Normalization};
 
 pub fn get_default() -> lib::Config {
     lib::Config {
@@ -13,11 +16,34 @@ pub fn get_default() -> lib::Config {
         timeinfo: false,
         once: false,
         static_mode: false,
-        separator: '.',
-        filename_separators: 1,
-        begin_var: '{',
-        end_var: '}',
-        last_token: ',',
+        separator: ".".into(),
+        filename_separators: None,
+        detect_extensions: false,
+        extensions: vec![],
+        begin_var: "{".into(),
+        end_var: "}".into(),
+        last_token: ",".into(),
+        escape: '\\',
+        settle: 0,
+        min_age: 0,
+        check_open: false,
+        max_depth: None,
+        follow_symlinks: SymlinkPolicy::Files,
+        resolve_symlinks: false,
+        dir_options: HashMap::new(),
+        prune_empty_dirs: false,
+        prune_grace: 10000,
+        hooks: lib::Hooks::default(),
+        script: None,
+        script_timeout: 100,
+        case_insensitive_codes: false,
+        dest_case: DestCase::Preserve,
+        sanitize: Sanitize::Posix,
+        unicode_normalization: Normalization::None,
+        ascii: Ascii::None,
+        mime_routes: HashMap::new(),
+        duplicates: Duplicates::Ignore,
+        database: None,
     }
 }
 
@@ -32,16 +58,39 @@ pub fn get_build_default() -> lib::BuildConfig {
             default
                 .codes
                 .iter()
-                .map(|tuple| (tuple.0.to_owned(), tuple.1.to_owned()))
+                .map(|tuple| (tuple.0.to_owned(), tuple.1.meaning().to_owned()))
                 .collect(),
         ),
         timeinfo: default.timeinfo,
         once: default.once,
         static_mode: default.static_mode,
-        separator: Some(default.separator),
-        filename_separators: Some(default.filename_separators),
-        begin_var: Some(default.begin_var),
-        end_var: Some(default.end_var),
-        last_token: Some(default.last_token),
+        separator: Some(default.separator.alternatives().to_vec()),
+        filename_separators: default.filename_separators,
+        detect_extensions: default.detect_extensions,
+        extensions: Some(default.extensions),
+        begin_var: Some(default.begin_var.alternatives().to_vec()),
+        end_var: Some(default.end_var.alternatives().to_vec()),
+        last_token: Some(default.last_token.alternatives().to_vec()),
+        escape: Some(default.escape),
+        settle: Some(default.settle),
+        min_age: Some(default.min_age),
+        check_open: default.check_open,
+        max_depth: default.max_depth,
+        follow_symlinks: Some(default.follow_symlinks),
+        resolve_symlinks: default.resolve_symlinks,
+        dir_options: Some(default.dir_options),
+        prune_empty_dirs: default.prune_empty_dirs,
+        prune_grace: Some(default.prune_grace),
+        hooks: Some(default.hooks),
+        script: default.script,
+        script_timeout: Some(default.script_timeout),
+        case_insensitive_codes: default.case_insensitive_codes,
+        dest_case: Some(default.dest_case),
+        sanitize: Some(default.sanitize),
+        unicode_normalization: Some(default.unicode_normalization),
+        ascii: Some(default.ascii),
+        mime_routes: Some(default.mime_routes),
+        duplicates: Some(default.duplicates),
+        database: default.database,
     }
 }
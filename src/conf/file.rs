@@ -7,10 +7,11 @@ use super::lib;
 
 macro_rules! replace_value {
     ($fromfile:expr, $config:expr, $field:ident, $declared:expr, $default:expr) => {
-        {if !$declared[lib::which_declared!(quote::quote!($field).to_string().as_str())] && !$fromfile.$field.is_none() {
-            $config.$field = $fromfile.$field.unwrap();
-        } else if !$declared[lib::which_declared!(quote::quote!($field).to_string().as_str())] {
-            $config.$field = $default.$field;
+        {if !$declared[lib::which_declared!(quote::quote!($field).to_string().as_str())] {
+            match $fromfile.$field {
+                Some(value) => $config.$field = value,
+                None => $config.$field = $default.$field,
+            }
         }}
     };
 
@@ -66,10 +67,48 @@ impl lib::Config {
                         timeinfo,
                         static_mode,
                         separator,
-                        filename_separators,
+                        detect_extensions,
+                        extensions,
                         begin_var,
-                        end_var
+                        end_var,
+                        last_token,
+                        escape,
+                        settle,
+                        min_age,
+                        check_open,
+                        follow_symlinks,
+                        resolve_symlinks,
+                        dir_options,
+                        prune_empty_dirs,
+                        prune_grace,
+                        hooks,
+                        script_timeout,
+                        case_insensitive_codes,
+                        dest_case,
+                        sanitize,
+                        unicode_normalization,
+                        ascii,
+                        mime_routes,
+                        duplicates
                     );
+
+                    // No depth limit is a valid value, so it can't go through replace_value
+                    if !declared[lib::which_declared!("max_depth")] {
+                        self.max_depth = from_file.max_depth.or(default.max_depth);
+                    }
+                    // Without it, the file name is found from its extension, or
+                    // has one separator
+                    if !declared[lib::which_declared!("filename_separators")] {
+                        self.filename_separators = from_file
+                            .filename_separators
+                            .or(default.filename_separators);
+                    }
+                    if !declared[lib::which_declared!("script")] {
+                        self.script = from_file.script.or(default.script);
+                    }
+                    if !declared[lib::which_declared!("database")] {
+                        self.database = from_file.database.or(default.database);
+                    }
                 }
                 Err(e) => {
                     log::error!(
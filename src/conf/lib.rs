@@ -1,9 +1,16 @@
 use serde::{Deserialize, Serialize};
 
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use super::defaults;
+use crate::dedupe::Duplicates;
+use crate::resolve::Code;
+use crate::sanitize::Sanitize;
+use crate::syntax::{Pattern, Syntax};
+use crate::unicode::{Ascii, Normalization};
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -13,21 +20,189 @@ pub struct Config {
     pub static_mode: bool,
     pub dest: PathBuf,
     pub dirs: HashSet<PathBuf>,
-    pub codes: HashMap<String, String>,
-    pub separator: char,
-    pub filename_separators: usize,
-    pub begin_var: char,
-    pub end_var: char,
-    pub last_token: char,
+    pub codes: HashMap<String, Code>,
+    pub separator: Pattern,
+    pub filename_separators: Option<usize>,
+    pub detect_extensions: bool,
+    pub extensions: Vec<String>,
+    pub begin_var: Pattern,
+    pub end_var: Pattern,
+    pub last_token: Pattern,
+    pub escape: char,
+    pub settle: usize,
+    pub min_age: usize,
+    pub check_open: bool,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: SymlinkPolicy,
+    pub resolve_symlinks: bool,
+    pub dir_options: HashMap<PathBuf, DirOptions>,
+    pub prune_empty_dirs: bool,
+    pub prune_grace: usize,
+    pub hooks: Hooks,
+    pub script: Option<String>,
+    pub script_timeout: usize,
+    pub case_insensitive_codes: bool,
+    pub dest_case: DestCase,
+    pub sanitize: Sanitize,
+    pub unicode_normalization: Normalization,
+    pub ascii: Ascii,
+    // Where files without codes go, following their MIME type
+    pub mime_routes: HashMap<String, PathBuf>,
+    pub duplicates: Duplicates,
+    // Where moves are recorded, relative to the destination
+    pub database: Option<PathBuf>,
 }
 
-impl Config {
+impl Default for Config {
     #[inline]
-    pub fn default() -> Self {
+    fn default() -> Self {
         defaults::get_default()
     }
 }
 
+impl Config {
+    pub fn syntax(&self) -> Syntax {
+        Syntax {
+            separator: self.separator.clone(),
+            begin_var: self.begin_var.clone(),
+            end_var: self.end_var.clone(),
+            last_token: self.last_token.clone(),
+            escape: self.escape,
+            filename_separators: self.filename_separators,
+            detect_extensions: self.detect_extensions,
+            extensions: self.extensions.clone(),
+            sanitize: self.sanitize,
+            unicode_normalization: self.unicode_normalization,
+            ascii: self.ascii,
+        }
+    }
+
+    // Where moves are recorded, if they are
+    pub fn database_path(&self) -> Option<PathBuf> {
+        let database = self.database.as_ref()?;
+        match shellexpand::full(&database.to_string_lossy()) {
+            Ok(expanded) => Some(self.dest.join(expanded.as_ref())),
+            Err(e) => {
+                log::warn!("Error while expanding database path : {}", e);
+                Some(self.dest.join(database))
+            }
+        }
+    }
+
+    // Scanning options for a watched directory, with its own options overriding the global ones
+    pub fn scan_options(&self, dir: &Path) -> (Option<usize>, SymlinkPolicy) {
+        match self.dir_options.get(dir) {
+            Some(options) => (
+                options.max_depth.or(self.max_depth),
+                options.follow_symlinks.unwrap_or(self.follow_symlinks),
+            ),
+            None => (self.max_depth, self.follow_symlinks),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    // Symlinks are ignored
+    Skip,
+    // Symlinks to files are handled, symlinks to directories are ignored
+    Files,
+    // Symlinks to directories are followed too
+    All,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "files" => Ok(Self::Files),
+            "all" => Ok(Self::All),
+            _ => Err(format!("invalid symlink policy `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Skip => write!(f, "skip"),
+            Self::Files => write!(f, "files"),
+            Self::All => write!(f, "all"),
+        }
+    }
+}
+
+// How the directories files are moved to are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DestCase {
+    // As the codes' meanings are written
+    Preserve,
+    Lower,
+    // Each word capitalized
+    Title,
+}
+
+impl FromStr for DestCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Self::Preserve),
+            "lower" => Ok(Self::Lower),
+            "title" => Ok(Self::Title),
+            _ => Err(format!("invalid destination case `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for DestCase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Preserve => write!(f, "preserve"),
+            Self::Lower => write!(f, "lower"),
+            Self::Title => write!(f, "title"),
+        }
+    }
+}
+
+// Commands run by the shell at some points of the job
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    // Run before each move, to accept it, skip it or change where the file goes
+    pub pre_move: Vec<String>,
+    // Run after each moved file
+    pub post_move: Vec<String>,
+    // Run after each loop where files were moved
+    pub post_loop: Vec<String>,
+    // In ms, 0 means no timeout
+    pub timeout: usize,
+    // 0 means no limit
+    pub max_concurrent: usize,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre_move: vec![],
+            post_move: vec![],
+            post_loop: vec![],
+            timeout: 30000,
+            max_concurrent: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DirOptions {
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: Option<SymlinkPolicy>,
+}
+
 #[derive(Clone, Debug)]
 pub struct BuildConfig {
     pub once: bool,
@@ -37,36 +212,82 @@ pub struct BuildConfig {
     pub dirs: Option<Vec<PathBuf>>,
     pub dest: Option<PathBuf>,
     pub codes: Option<Vec<(String, String)>>,
-    pub separator: Option<char>,
+    pub separator: Option<Vec<String>>,
     pub filename_separators: Option<usize>,
-    pub begin_var: Option<char>,
-    pub end_var: Option<char>,
-    pub last_token: Option<char>,
+    pub detect_extensions: bool,
+    pub extensions: Option<Vec<String>>,
+    pub begin_var: Option<Vec<String>>,
+    pub end_var: Option<Vec<String>>,
+    pub last_token: Option<Vec<String>>,
+    pub escape: Option<char>,
+    pub settle: Option<usize>,
+    pub min_age: Option<usize>,
+    pub check_open: bool,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: Option<SymlinkPolicy>,
+    pub resolve_symlinks: bool,
+    pub dir_options: Option<HashMap<PathBuf, DirOptions>>,
+    pub prune_empty_dirs: bool,
+    pub prune_grace: Option<usize>,
+    pub hooks: Option<Hooks>,
+    pub script: Option<String>,
+    pub script_timeout: Option<usize>,
+    pub case_insensitive_codes: bool,
+    pub dest_case: Option<DestCase>,
+    pub sanitize: Option<Sanitize>,
+    pub unicode_normalization: Option<Normalization>,
+    pub ascii: Option<Ascii>,
+    pub mime_routes: Option<HashMap<String, PathBuf>>,
+    pub duplicates: Option<Duplicates>,
+    pub database: Option<PathBuf>,
 }
 
-impl BuildConfig {
+impl Default for BuildConfig {
     #[inline]
-    pub fn default() -> Self {
+    fn default() -> Self {
         defaults::get_build_default()
     }
 }
 
-pub type DeclaredType = [bool; 13];
+pub type DeclaredType = [bool; 36];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSerDe {
-    pub separator: Option<char>,
+    pub separator: Option<Pattern>,
     pub filename_separators: Option<usize>,
-    pub begin_var: Option<char>,
-    pub end_var: Option<char>,
-    pub last_token: Option<char>,
+    pub detect_extensions: Option<bool>,
+    pub extensions: Option<Vec<String>>,
+    pub begin_var: Option<Pattern>,
+    pub end_var: Option<Pattern>,
+    pub last_token: Option<Pattern>,
+    pub escape: Option<char>,
     pub once: Option<bool>,
     pub timeinfo: Option<bool>,
     pub static_mode: Option<bool>,
     pub sleep: Option<usize>,
-    pub codes: Option<HashMap<String, String>>,
+    pub codes: Option<HashMap<String, Code>>,
     pub dest: Option<PathBuf>,
     pub dirs: Option<HashSet<PathBuf>>,
+    pub settle: Option<usize>,
+    pub min_age: Option<usize>,
+    pub check_open: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: Option<SymlinkPolicy>,
+    pub resolve_symlinks: Option<bool>,
+    pub dir_options: Option<HashMap<PathBuf, DirOptions>>,
+    pub prune_empty_dirs: Option<bool>,
+    pub prune_grace: Option<usize>,
+    pub hooks: Option<Hooks>,
+    pub script: Option<String>,
+    pub script_timeout: Option<usize>,
+    pub case_insensitive_codes: Option<bool>,
+    pub dest_case: Option<DestCase>,
+    pub sanitize: Option<Sanitize>,
+    pub unicode_normalization: Option<Normalization>,
+    pub ascii: Option<Ascii>,
+    pub mime_routes: Option<HashMap<String, PathBuf>>,
+    pub duplicates: Option<Duplicates>,
+    pub database: Option<PathBuf>,
 }
 
 macro_rules! test_path {
@@ -97,6 +318,29 @@ macro_rules! which_declared {
             "static_mode" => 7,
             "separator" => 8,
             "filename_separators" => 9,
+            "settle" => 13,
+            "min_age" => 14,
+            "check_open" => 15,
+            "max_depth" => 16,
+            "follow_symlinks" => 17,
+            "resolve_symlinks" => 18,
+            "dir_options" => 19,
+            "prune_empty_dirs" => 20,
+            "prune_grace" => 21,
+            "hooks" => 22,
+            "script" => 23,
+            "script_timeout" => 24,
+            "escape" => 25,
+            "extensions" => 26,
+            "detect_extensions" => 27,
+            "case_insensitive_codes" => 28,
+            "dest_case" => 29,
+            "sanitize" => 30,
+            "unicode_normalization" => 31,
+            "ascii" => 32,
+            "mime_routes" => 33,
+            "duplicates" => 34,
+            "database" => 35,
             _ => 8,
         }
     };
@@ -3,3 +3,4 @@ pub mod cli;
 pub mod defaults;
 pub mod file;
 pub mod lib;
+pub mod references;
@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::resolve::{Code, SegmentContext};
+use crate::run;
+use crate::syntax::{self, Node, Syntax};
+
+// Expands the codes meanings refer to, like `frh: "{fr} {hst}"`
+struct References<'a> {
+    codes: &'a HashMap<String, Code>,
+    syntax: &'a Syntax,
+    lowercase: bool,
+    // Meanings already expanded, or why they can't be
+    expanded: RefCell<HashMap<String, Result<String, String>>>,
+    // Codes being expanded, to find cycles
+    visiting: RefCell<Vec<String>>,
+    // Variables which don't refer to any code, kept as they're written
+    broken: RefCell<BTreeSet<String>>,
+}
+
+impl References<'_> {
+    fn expand(&self, meaning: &str) -> Result<String, String> {
+        if !self.syntax.begin_var.is_in(meaning) {
+            return Ok(meaning.to_owned());
+        }
+
+        // The first code which can't be expanded is why this one can't either
+        let error = RefCell::new(None);
+        let resolver = |code: &str, _: &SegmentContext| {
+            let code = if self.lowercase {
+                code.to_lowercase()
+            } else {
+                code.to_owned()
+            };
+            self.codes.get(&code)?;
+
+            match self.meaning(&code) {
+                Ok(meaning) => Some(meaning),
+                Err(e) => {
+                    error.borrow_mut().get_or_insert(e);
+                    Some(String::new())
+                }
+            }
+        };
+
+        // Only the variables are replaced : the rest, escapes included, is
+        // kept as it's written
+        let mut result = String::new();
+        for node in syntax::parse(meaning, &syntax::tokenize(meaning, self.syntax)) {
+            let span = node.span();
+            let written = &meaning[span.start..span.end];
+
+            match node {
+                Node::Variable(..) => match run::expand_strict(written, &resolver, self.syntax) {
+                    Ok(expanded) => result.push_str(&expanded),
+                    Err(_) => {
+                        self.broken.borrow_mut().insert(written.to_owned());
+                        result.push_str(written);
+                    }
+                },
+                _ => result.push_str(written),
+            }
+
+            if let Some(e) = error.borrow_mut().take() {
+                return Err(e);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn meaning(&self, key: &str) -> Result<String, String> {
+        if let Some(result) = self.expanded.borrow().get(key) {
+            return result.clone();
+        }
+
+        if let Some(start) = self.visiting.borrow().iter().position(|code| code == key) {
+            let cycle = self.visiting.borrow()[start..].join("` → `");
+            return Err(format!("`{}` → `{}` refer to each other", cycle, key));
+        }
+
+        self.visiting.borrow_mut().push(key.to_owned());
+        let result = self.expand(self.codes[key].meaning());
+        self.visiting.borrow_mut().pop();
+
+        self.expanded
+            .borrow_mut()
+            .insert(key.to_owned(), result.clone());
+        result
+    }
+}
+
+fn expand_all(codes: &HashMap<String, Code>, references: &References) -> HashMap<String, Code> {
+    let mut keys: Vec<&String> = codes.keys().collect();
+    keys.sort();
+
+    let mut expanded = HashMap::new();
+    for key in keys {
+        let code = &codes[key];
+        let meaning = match references.expand(code.meaning()) {
+            Ok(meaning) => meaning,
+            Err(e) => {
+                log::warn!(
+                    "Shortcut `{:#}={:#}` can't be expanded : {} ! Not using it",
+                    key,
+                    code.meaning(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        let code = match code {
+            Code::Meaning(_) => Code::Meaning(meaning),
+            Code::Entry { aliases, codes, .. } => Code::Entry {
+                name: meaning,
+                aliases: aliases.clone(),
+                codes: expand_all(codes, references),
+            },
+        };
+        expanded.insert(key.to_owned(), code);
+    }
+
+    expanded
+}
+
+// Replaces the variables in meanings by the global codes they refer to, in
+// namespaces too. Variables referring to codes that don't exist are kept as
+// they're written, and codes referring to themselves are left out
+pub fn expand(
+    codes: &HashMap<String, Code>,
+    syntax: &Syntax,
+    lowercase: bool,
+) -> HashMap<String, Code> {
+    let references = References {
+        codes,
+        syntax,
+        lowercase,
+        expanded: RefCell::new(HashMap::new()),
+        visiting: RefCell::new(vec![]),
+        broken: RefCell::new(BTreeSet::new()),
+    };
+
+    let expanded = expand_all(codes, &references);
+    for variable in references.broken.into_inner() {
+        log::warn!(
+            "`{:#}` doesn't refer to any shortcut ! Keeping it as it is",
+            variable
+        );
+    }
+    expanded
+}
@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Checks that a directory or file name computed from a file name is only
+// one name, so that it can't go elsewhere than below the destination
+pub fn check_component(component: &str) -> Result<(), String> {
+    if component.contains('\0') {
+        return Err(format!(
+            "`{}` contains a NUL character",
+            component.escape_default()
+        ));
+    }
+
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(format!(
+            "`{}` isn't a valid directory or file name",
+            component
+        )),
+    }
+}
+
+// Checks that `target` is inside `dest`, both as written and once symlinks
+// are resolved, since a directory inside `dest` could link to outside of it
+pub fn check_inside(target: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let outside = || format!("Target {:?} isn't inside destination {:?}", target, dest);
+
+    let relative = target.strip_prefix(dest).map_err(|_| outside())?;
+    if relative.as_os_str().is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(outside().into());
+    }
+
+    // The target doesn't exist yet, but the directories above it may
+    let target = target.parent().ok_or_else(outside)?;
+    if !canonicalize(target)?.starts_with(canonicalize(dest)?) {
+        return Err(outside().into());
+    }
+
+    Ok(())
+}
+
+// Resolves the symlinks of the part of `path` which exists. The destination
+// of a directory-local configuration may not exist until files are moved there
+fn canonicalize(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    // The last ancestor of a relative path is empty, and stands for `.`
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.as_os_str().is_empty() || ancestor.exists())
+        .unwrap();
+    let resolved = if existing.as_os_str().is_empty() {
+        fs::canonicalize(".")?
+    } else {
+        fs::canonicalize(existing)?
+    };
+
+    Ok(resolved.join(path.strip_prefix(existing)?))
+}
@@ -0,0 +1,252 @@
+use chrono::{Local, NaiveDate, TimeZone};
+use rusqlite::{params, Connection};
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Each part of a file name is a row of `parts`, so that files can be found
+// by code or by meaning. Times are Unix timestamps
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS moves (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        source_dir TEXT NOT NULL,
+        target TEXT NOT NULL,
+        created INTEGER,
+        modified INTEGER,
+        moved INTEGER NOT NULL,
+        hash TEXT
+    );
+    CREATE INDEX IF NOT EXISTS moves_target ON moves (target);
+    CREATE TABLE IF NOT EXISTS parts (
+        move INTEGER NOT NULL REFERENCES moves (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        code TEXT NOT NULL,
+        segment TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS parts_code ON parts (code);
+";
+
+// A file that was moved, and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    // The file name before it was moved
+    pub name: String,
+    pub source_dir: PathBuf,
+    pub codes: Vec<String>,
+    pub segments: Vec<String>,
+    pub target: PathBuf,
+    pub created: Option<i64>,
+    pub modified: Option<i64>,
+    pub moved: i64,
+    // Its BLAKE3 hash, in hexadecimal
+    pub hash: Option<String>,
+}
+
+// What to search for. Files must match all of the criteria given
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    // One of its codes, whatever its case
+    pub code: Option<String>,
+    // Text one of its parts was expanded to contains
+    pub meaning: Option<String>,
+    // Text its original name contains
+    pub name: Option<String>,
+    // Moved at that time or later
+    pub since: Option<i64>,
+    // Moved before that time
+    pub until: Option<i64>,
+}
+
+pub fn timestamp(time: SystemTime) -> Option<i64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs() as i64)
+}
+
+// The first second of `date`, in the local time zone
+pub fn day_start(date: NaiveDate) -> Option<i64> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+        .map(|datetime| datetime.timestamp())
+}
+
+// The database at `path`, and the files SQLite keeps next to it
+pub fn files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![path.to_owned()];
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        files.push(file.into());
+    }
+    files
+}
+
+fn text(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// Record of the files moved, kept in an SQLite database
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Records a move. The file that was at its target before was replaced
+    pub fn record(&self, moved: &Move) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "DELETE FROM moves WHERE target = ?1",
+            params![text(&moved.target)],
+        )?;
+        transaction.execute(
+            "INSERT INTO moves (name, source_dir, target, created, modified, moved, hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                moved.name,
+                text(&moved.source_dir),
+                text(&moved.target),
+                moved.created,
+                moved.modified,
+                moved.moved,
+                moved.hash,
+            ],
+        )?;
+
+        // Files sent by a script or by their type have segments but no codes
+        let id = transaction.last_insert_rowid();
+        for position in 0..moved.codes.len().max(moved.segments.len()) {
+            transaction.execute(
+                "INSERT INTO parts (move, position, code, segment) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    id,
+                    position as i64,
+                    moved.codes.get(position).map_or("", String::as_str),
+                    moved.segments.get(position).map_or("", String::as_str),
+                ],
+            )?;
+        }
+
+        transaction.commit()
+    }
+
+    // Forgets the files which aren't where they were moved to anymore.
+    // Returns how many of them there were
+    pub fn sync(&self) -> rusqlite::Result<usize> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare("SELECT id, target FROM moves")?;
+        let gone: Vec<i64> = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(Result::ok)
+            .filter(|(_, target)| !Path::new(target).exists())
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in &gone {
+            connection.execute("DELETE FROM moves WHERE id = ?1", params![id])?;
+        }
+
+        Ok(gone.len())
+    }
+
+    // The files matching `query`, in the order they were moved
+    pub fn search(&self, query: &Query) -> rusqlite::Result<Vec<Move>> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare(
+            "SELECT id, name, source_dir, target, created, modified, moved, hash FROM moves
+             WHERE (?1 IS NULL OR EXISTS (
+                    SELECT 1 FROM parts WHERE move = moves.id AND code = ?1 COLLATE NOCASE))
+               AND (?2 IS NULL OR EXISTS (
+                    SELECT 1 FROM parts WHERE move = moves.id AND instr(lower(segment), lower(?2))))
+               AND (?3 IS NULL OR instr(lower(name), lower(?3)))
+               AND (?4 IS NULL OR moved >= ?4)
+               AND (?5 IS NULL OR moved < ?5)
+             ORDER BY moved, id",
+        )?;
+        let rows = statement.query_map(
+            params![
+                query.code,
+                query.meaning,
+                query.name,
+                query.since,
+                query.until
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    Move {
+                        name: row.get(1)?,
+                        source_dir: PathBuf::from(row.get::<_, String>(2)?),
+                        codes: vec![],
+                        segments: vec![],
+                        target: PathBuf::from(row.get::<_, String>(3)?),
+                        created: row.get(4)?,
+                        modified: row.get(5)?,
+                        moved: row.get(6)?,
+                        hash: row.get(7)?,
+                    },
+                ))
+            },
+        )?;
+
+        let mut parts = connection
+            .prepare("SELECT code, segment FROM parts WHERE move = ?1 ORDER BY position")?;
+        let mut result = vec![];
+        for row in rows {
+            let (id, mut moved) = row?;
+            for part in parts.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (code, segment) = part?;
+                moved.codes.push(code);
+                moved.segments.push(segment);
+            }
+            result.push(moved);
+        }
+
+        Ok(result)
+    }
+}
+
+// Prints the files matching `query`, one per line : when it was moved, where
+// it is and where it came from. Files which disappeared are left out, but
+// only forgotten by the daemon
+pub fn report(path: &Path, query: &Query) -> rusqlite::Result<()> {
+    let database = Database::open(path)?;
+
+    for moved in database
+        .search(query)?
+        .into_iter()
+        .filter(|moved| moved.target.exists())
+    {
+        let when = Local
+            .timestamp_opt(moved.moved, 0)
+            .single()
+            .map(|when| when.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!(
+            "{}  {}  (from {})",
+            when,
+            moved.target.display(),
+            moved.source_dir.join(&moved.name).display()
+        );
+    }
+
+    Ok(())
+}
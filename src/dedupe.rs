@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::walk;
+
+// What's done with a file identical to one already in the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Duplicates {
+    // Files are moved without looking for duplicates
+    Ignore,
+    // The file is moved anyway, and the duplicate is logged
+    Keep,
+    // The file isn't moved, but deleted
+    Delete,
+    // The file is replaced by a hard link to the one already there
+    Hardlink,
+}
+
+impl FromStr for Duplicates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "keep" => Ok(Self::Keep),
+            "delete" => Ok(Self::Delete),
+            "hardlink" => Ok(Self::Hardlink),
+            _ => Err(format!("invalid duplicates policy `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ignore => write!(f, "ignore"),
+            Self::Keep => write!(f, "keep"),
+            Self::Delete => write!(f, "delete"),
+            Self::Hardlink => write!(f, "hardlink"),
+        }
+    }
+}
+
+pub fn hash(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize())
+}
+
+// A directory of a destination, as it was when it was listed
+struct Dir {
+    modified: SystemTime,
+    subdirs: Vec<PathBuf>,
+    // Its files, with their size
+    files: Vec<(PathBuf, u64)>,
+}
+
+// The files of a destination by size. Empty files are all identical, but
+// aren't duplicates of each other
+#[derive(Default)]
+struct Listing {
+    dirs: HashMap<PathBuf, Dir>,
+    sizes: HashMap<u64, Vec<PathBuf>>,
+    // Whether it was updated during this loop
+    fresh: bool,
+}
+
+impl Listing {
+    // Lists again the directories of `dest` modified since they were listed.
+    // `ignored` files are left out
+    fn update(&mut self, dest: &Path, ignored: &[PathBuf]) {
+        let mut seen = HashSet::new();
+        let mut queue = vec![dest.to_owned()];
+
+        while let Some(dir) = queue.pop() {
+            // Read before listing, so that files added meanwhile are found later
+            let modified = match fs::metadata(&dir).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+
+            if self
+                .dirs
+                .get(&dir)
+                .is_none_or(|listed| listed.modified != modified)
+            {
+                let (subdirs, files) = walk::list(&dir);
+                let files = files
+                    .into_iter()
+                    .filter(|file| !ignored.contains(file))
+                    .filter_map(|file| match fs::metadata(&file) {
+                        Ok(metadata) if metadata.len() > 0 => Some((file, metadata.len())),
+                        _ => None,
+                    })
+                    .collect();
+                self.dirs.insert(
+                    dir.clone(),
+                    Dir {
+                        modified,
+                        subdirs,
+                        files,
+                    },
+                );
+            }
+
+            queue.extend(self.dirs[&dir].subdirs.iter().cloned());
+            seen.insert(dir);
+        }
+
+        self.dirs.retain(|dir, _| seen.contains(dir));
+        self.sizes.clear();
+        for (file, size) in self.dirs.values().flat_map(|dir| &dir.files) {
+            self.sizes.entry(*size).or_default().push(file.clone());
+        }
+        self.fresh = true;
+    }
+}
+
+// Files of each destination, kept from a loop to the next
+#[derive(Default)]
+struct Listed {
+    listings: HashMap<PathBuf, Listing>,
+    // Where files are being moved to during this loop, and their hash
+    pending: HashMap<PathBuf, blake3::Hash>,
+}
+
+// A hash, with the size and modification time of the file at that moment
+type Hashed = (u64, Option<SystemTime>, blake3::Hash);
+
+// Hashes of the files in the destinations. Only files having the same size
+// as a file being moved are hashed, and only once while they're unchanged
+#[derive(Default)]
+pub struct Index {
+    listed: Mutex<Listed>,
+    hashes: Mutex<HashMap<PathBuf, Hashed>>,
+    // Files of the destinations which are never duplicates, like the database
+    ignored: Mutex<Vec<PathBuf>>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Updates the destinations when they're next looked into, since files
+    // could have been added or removed by hand
+    pub fn refresh(&self) {
+        let mut listed = self.listed.lock().unwrap();
+        for listing in listed.listings.values_mut() {
+            listing.fresh = false;
+        }
+        listed.pending.clear();
+    }
+
+    // Sets the files which are never duplicates, and lists the destinations
+    // again without them
+    pub fn ignore(&self, files: Vec<PathBuf>) {
+        *self.ignored.lock().unwrap() = files;
+        let mut listed = self.listed.lock().unwrap();
+        listed.listings.clear();
+        listed.pending.clear();
+    }
+
+    fn hash(&self, path: &Path) -> io::Result<(u64, blake3::Hash)> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().ok();
+
+        if let Some((size, when, hash)) = self.hashes.lock().unwrap().get(path) {
+            if *size == metadata.len() && *when == modified {
+                return Ok((*size, *hash));
+            }
+        }
+
+        let hash = hash(path)?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (metadata.len(), modified, hash));
+        Ok((metadata.len(), hash))
+    }
+
+    // The file of `dest` identical to `source`, and the hash of `source`. When
+    // there's none, `source` is expected to be moved to `target`, and
+    // duplicates of it will be found there, even if they're handled at the
+    // same time
+    pub fn claim(
+        &self,
+        dest: &Path,
+        source: &Path,
+        target: &Path,
+    ) -> io::Result<(Option<PathBuf>, blake3::Hash)> {
+        // Sources are moved away, so their hash isn't kept
+        let metadata = fs::metadata(source)?;
+        let size = metadata.len();
+        let hash = hash(source)?;
+        if size == 0 {
+            return Ok((None, hash));
+        }
+
+        let candidates: Vec<(PathBuf, Option<blake3::Hash>)> = {
+            let ignored = self.ignored.lock().unwrap();
+            let mut listed = self.listed.lock().unwrap();
+            let Listed { listings, pending } = &mut *listed;
+            let listing = listings.entry(dest.to_owned()).or_default();
+            if !listing.fresh {
+                listing.update(dest, &ignored);
+            }
+
+            listing.sizes.get(&size).map_or(vec![], |files| {
+                files
+                    .iter()
+                    .map(|file| (file.to_owned(), pending.get(file).copied()))
+                    .collect()
+            })
+        };
+
+        // Files are hashed without holding the lock, so that other ones are
+        // handled meanwhile
+        for (file, other) in &candidates {
+            let other = match other {
+                Some(other) => Ok(*other),
+                None => self.hash(file).map(|(_, other)| other),
+            };
+            match other {
+                Ok(other) if other == hash && file != source => {
+                    return Ok((Some(file.to_owned()), hash));
+                }
+                // Files removed since the destination was listed
+                _ => (),
+            }
+        }
+
+        let mut listed = self.listed.lock().unwrap();
+        let Listed { listings, pending } = &mut *listed;
+        let files = listings
+            .entry(dest.to_owned())
+            .or_default()
+            .sizes
+            .entry(size)
+            .or_default();
+
+        // Files claimed meanwhile
+        let claimed = files
+            .iter()
+            .filter(|file| !candidates.iter().any(|(candidate, _)| candidate == *file))
+            .find(|file| pending.get(*file) == Some(&hash));
+        if let Some(file) = claimed {
+            return Ok((Some(file.to_owned()), hash));
+        }
+
+        // Moving the file keeps its modification time
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(target.to_owned(), (size, metadata.modified().ok(), hash));
+        files.push(target.to_owned());
+        pending.insert(target.to_owned(), hash);
+        Ok((None, hash))
+    }
+
+    // Forgets `target`, claimed in `dest` by a file which couldn't be moved
+    // there, so that it isn't taken for a duplicate of the next ones
+    pub fn release(&self, dest: &Path, target: &Path) {
+        let mut listed = self.listed.lock().unwrap();
+        if listed.pending.remove(target).is_none() {
+            return;
+        }
+        if let Some(listing) = listed.listings.get_mut(dest) {
+            for files in listing.sizes.values_mut() {
+                files.retain(|file| file != target);
+            }
+        }
+        self.hashes.lock().unwrap().remove(target);
+    }
+}
+
+// What hard links to the same file share
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// The groups of identical files in `dest` but `ignored`, each sorted, and
+// ordered by their first file. Hard links to the same file aren't duplicates
+pub fn find(dest: &Path, ignored: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    let mut groups = vec![];
+    let mut listing = Listing::default();
+    listing.update(dest, ignored);
+
+    for (_, files) in listing
+        .sizes
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+    {
+        let mut by_hash: HashMap<blake3::Hash, Vec<PathBuf>> = HashMap::new();
+        let mut seen = HashSet::new();
+
+        for file in files {
+            if let Some(id) = file_id(&file) {
+                if !seen.insert(id) {
+                    continue;
+                }
+            }
+
+            match hash(&file) {
+                Ok(hash) => by_hash.entry(hash).or_default().push(file),
+                Err(e) => log::warn!("Can't read `{:?}` : {}", file, e),
+            }
+        }
+
+        groups.extend(by_hash.into_values().filter(|files| files.len() > 1));
+    }
+
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+    groups
+}
+
+// Prints the groups of identical files in `dest` but `ignored`, and how much
+// space they take for nothing
+pub fn report(dest: &Path, ignored: &[PathBuf]) {
+    let groups = find(dest, ignored);
+
+    let (mut duplicates, mut wasted) = (0, 0);
+    for group in &groups {
+        for file in group {
+            println!("{}", file.display());
+        }
+        println!();
+
+        let size = fs::metadata(&group[0]).map_or(0, |metadata| metadata.len());
+        duplicates += group.len() - 1;
+        wasted += size * (group.len() as u64 - 1);
+    }
+
+    println!(
+        "{} files could be removed, freeing {} bytes",
+        duplicates, wasted
+    );
+}
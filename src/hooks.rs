@@ -0,0 +1,256 @@
+use serde::Serialize;
+
+use std::error::Error;
+use std::io::prelude::*;
+use std::path;
+use std::process::{self, Command, Stdio};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread::{self, sleep};
+use std::time;
+
+use crate::conf::lib::Hooks;
+use crate::run::NewName;
+
+// What a `pre_move` hook decided to do with a file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    Accept,
+    Skip,
+    Rewrite(path::PathBuf),
+}
+
+// What a `pre_move` hook gets on its standard input, as JSON
+#[derive(Serialize)]
+struct PreMove<'a> {
+    source: &'a path::Path,
+    target: &'a path::Path,
+    codes: &'a [String],
+    segments: &'a [String],
+}
+
+// Runs hooks, without running more than `max_concurrent` of them at the same time
+#[derive(Debug, Default)]
+pub struct Runner {
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn limited<T>(&self, max_concurrent: usize, f: impl FnOnce() -> T) -> T {
+        {
+            let mut running = self.running.lock().unwrap();
+            while max_concurrent > 0 && *running >= max_concurrent {
+                running = self.freed.wait(running).unwrap();
+            }
+            *running += 1;
+        }
+
+        let result = f();
+
+        *self.running.lock().unwrap() -= 1;
+        self.freed.notify_one();
+
+        result
+    }
+
+    // Returns whether the hook succeeded
+    pub fn run(&self, command: &str, env: &[(String, String)], hooks: &Hooks) -> bool {
+        self.limited(hooks.max_concurrent, || {
+            run(command, env, None, hooks.timeout).is_some_and(|(status, _)| status.success())
+        })
+    }
+
+    // Asks a `pre_move` hook what to do with a file. It replies `accept`,
+    // `skip`, or the path the file should be moved to instead
+    pub fn filter(
+        &self,
+        command: &str,
+        source: &path::Path,
+        new_name: &NewName,
+        env: &[(String, String)],
+        hooks: &Hooks,
+    ) -> Result<Decision, Box<dyn Error>> {
+        let input = serde_json::to_string(&PreMove {
+            source,
+            target: &new_name.file,
+            codes: &new_name.codes,
+            segments: &new_name.segments,
+        })?;
+
+        let (status, output) = self
+            .limited(hooks.max_concurrent, || {
+                run(command, env, Some(input), hooks.timeout)
+            })
+            .ok_or_else(|| format!("Hook `{}` failed", command))?;
+
+        if !status.success() {
+            return Err(format!("Hook `{}` exited with {}", command, status).into());
+        }
+
+        Ok(match output.trim() {
+            "accept" => Decision::Accept,
+            "skip" => Decision::Skip,
+            "" => return Err(format!("Hook `{}` didn't reply anything", command).into()),
+            target => Decision::Rewrite(path::PathBuf::from(target)),
+        })
+    }
+}
+
+// Makes a target given by a hook absolute, and checks it stays inside the destination
+pub fn validate_target(
+    target: &path::Path,
+    dest: &path::Path,
+) -> Result<path::PathBuf, Box<dyn Error>> {
+    let target = dest.join(target);
+
+    let escapes = target.components().any(|component| {
+        matches!(
+            component,
+            path::Component::ParentDir | path::Component::CurDir
+        )
+    });
+    if escapes || !target.starts_with(dest) || target.file_name().is_none() || target == dest {
+        return Err(format!("Target {:?} isn't inside destination {:?}", target, dest).into());
+    }
+
+    Ok(target)
+}
+
+// Environment variables describing a move, given to hooks
+pub fn move_env(source: &path::Path, new_name: &NewName, separator: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("FCS_SOURCE".to_string(), source.display().to_string()),
+        (
+            "FCS_TARGET".to_string(),
+            new_name.file.display().to_string(),
+        ),
+        ("FCS_CODES".to_string(), new_name.codes.join(separator)),
+        (
+            "FCS_SEGMENTS".to_string(),
+            new_name.segments.join(path::MAIN_SEPARATOR_STR),
+        ),
+        (
+            "FCS_SEGMENT_COUNT".to_string(),
+            new_name.segments.len().to_string(),
+        ),
+    ];
+
+    for (index, segment) in new_name.segments.iter().enumerate() {
+        env.push((format!("FCS_SEGMENT_{}", index + 1), segment.to_owned()));
+    }
+
+    env
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+// Returns the exit status of the command, and its output if it was given an input
+fn run(
+    command: &str,
+    env: &[(String, String)],
+    input: Option<String>,
+    timeout: usize,
+) -> Option<(process::ExitStatus, String)> {
+    let mut shell = shell(command);
+    shell.envs(env.iter().map(|(key, value)| (key, value)));
+    if input.is_some() {
+        shell.stdin(Stdio::piped()).stdout(Stdio::piped());
+    } else {
+        shell.stdin(Stdio::null());
+    }
+
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            log::error!("Couldn't run hook `{}` : {}", command, e);
+            return None;
+        }
+    };
+
+    // Reading and writing in other threads, so that neither the hook nor us
+    // are stuck waiting for the other one. They aren't joined, since something
+    // the hook started in the background could keep its input or output open
+    if let Some((mut stdin, input)) = child.stdin.take().zip(input) {
+        thread::spawn(move || {
+            stdin.write_all(input.as_bytes()).ok();
+        });
+    }
+    let reader = child.stdout.take().map(|mut stdout| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).ok();
+            sender.send(output).ok();
+        });
+        receiver
+    });
+
+    let start = time::Instant::now();
+    let timeout = time::Duration::from_millis(timeout as u64);
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => {
+                log::info!("Hook `{}` exited with {}", command, status);
+                break status;
+            }
+            Ok(Some(status)) => {
+                log::warn!("Hook `{}` exited with {}", command, status);
+                break status;
+            }
+            Ok(None) => {
+                if !timeout.is_zero() && start.elapsed() >= timeout {
+                    log::warn!(
+                        "Hook `{}` timed out after {} ms, killing it",
+                        command,
+                        timeout.as_millis()
+                    );
+                    child.kill().ok();
+                    child.wait().ok();
+                    return None;
+                }
+
+                sleep(time::Duration::from_millis(10));
+            }
+            Err(e) => {
+                log::error!("Error while waiting for hook `{}` : {}", command, e);
+                return None;
+            }
+        }
+    };
+
+    let output = match reader {
+        Some(reader) if timeout.is_zero() => reader.recv().ok(),
+        Some(reader) => reader
+            .recv_timeout(timeout.saturating_sub(start.elapsed()))
+            .ok(),
+        None => Some(String::new()),
+    };
+
+    match output {
+        Some(output) => Some((status, output)),
+        None => {
+            log::warn!(
+                "Hook `{}` exited, but its output is still open after {} ms",
+                command,
+                timeout.as_millis()
+            );
+            None
+        }
+    }
+}
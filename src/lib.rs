@@ -2,5 +2,21 @@
 pub mod conf;
 pub use conf::args_file;
 
+pub mod case;
+pub mod confine;
+pub mod database;
+pub mod dedupe;
+pub mod hooks;
+pub mod local;
+pub mod mime;
+pub mod prune;
+pub mod resolve;
 pub mod run;
+pub mod sanitize;
+pub mod script;
+pub mod stable;
+pub mod syntax;
 pub mod test;
+pub mod transform;
+pub mod unicode;
+pub mod walk;
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::conf::lib::{Config, ConfigSerDe};
+
+// Configuration file of a directory inside the watched ones
+pub const FILE_NAME: &str = ".fcs.yml";
+
+// The `.fcs.yml` files of the directories holding `files`, up to the watched
+// directory `dir`
+pub fn find(dir: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let dirs: HashSet<&Path> = files
+        .iter()
+        .flat_map(|file| file.ancestors().skip(1))
+        .filter(|ancestor| ancestor.starts_with(dir))
+        .collect();
+
+    dirs.into_iter()
+        .map(|ancestor| ancestor.join(FILE_NAME))
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+// Configurations of the directories holding a `.fcs.yml`, layered over the
+// configurations of the directories above them, and over the global one
+#[derive(Default)]
+pub struct Local {
+    // When each file was modified, when it was read
+    read: Option<HashMap<PathBuf, Option<SystemTime>>>,
+    configs: HashMap<PathBuf, Config>,
+}
+
+impl Local {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Forgets the files read, so that they're layered over a new global configuration
+    pub fn clear(&mut self) {
+        self.read = None;
+        self.configs.clear();
+    }
+
+    // Reads the files again if some of them changed, appeared or disappeared
+    pub fn update(&mut self, files: &[PathBuf], global: &Config) {
+        if global.static_mode && self.read.is_some() {
+            return;
+        }
+
+        let modified: HashMap<PathBuf, Option<SystemTime>> = files
+            .iter()
+            .map(|file| {
+                let modified = fs::metadata(file).and_then(|metadata| metadata.modified());
+                (file.to_owned(), modified.ok())
+            })
+            .collect();
+        if self.read.as_ref() == Some(&modified) {
+            return;
+        }
+
+        if self.read.is_some() {
+            log::info!("Directory configurations changed ! Loading them");
+        }
+
+        // Directories above come first, so that the ones below are layered over them
+        let mut files: Vec<&PathBuf> = modified.keys().collect();
+        files.sort_by_key(|file| file.components().count());
+
+        self.configs.clear();
+        for file in files {
+            let dir = file.parent().unwrap();
+            let base = self.config(dir, global).clone();
+
+            if let Some(config) = layer(base, file) {
+                self.configs.insert(dir.to_owned(), config);
+            }
+        }
+
+        self.read = Some(modified);
+    }
+
+    // The configuration of the nearest directory holding `path` which has a `.fcs.yml`
+    pub fn config<'a>(&'a self, path: &Path, global: &'a Config) -> &'a Config {
+        path.ancestors()
+            .find_map(|dir| self.configs.get(dir))
+            .unwrap_or(global)
+    }
+}
+
+// `config` with the settings of `file` over it. Returns None if they can't be used
+fn layer(mut config: Config, file: &Path) -> Option<Config> {
+    let local = match fs::read_to_string(file)
+        .map_err(|e| e.to_string())
+        .and_then(|content| {
+            serde_yaml::from_str::<ConfigSerDe>(&content).map_err(|e| e.to_string())
+        }) {
+        Ok(local) => local,
+        Err(e) => {
+            log::error!("Can't read `{:?}` : {}. Not using it", file, e);
+            return None;
+        }
+    };
+
+    // Settings of the whole program, rather than of the files
+    let global_only = [
+        ("dirs", local.dirs.is_some()),
+        ("once", local.once.is_some()),
+        ("sleep", local.sleep.is_some()),
+        ("static_mode", local.static_mode.is_some()),
+        ("settle", local.settle.is_some()),
+        ("min_age", local.min_age.is_some()),
+        ("check_open", local.check_open.is_some()),
+        ("max_depth", local.max_depth.is_some()),
+        ("follow_symlinks", local.follow_symlinks.is_some()),
+        ("resolve_symlinks", local.resolve_symlinks.is_some()),
+        ("dir_options", local.dir_options.is_some()),
+        ("prune_empty_dirs", local.prune_empty_dirs.is_some()),
+        ("prune_grace", local.prune_grace.is_some()),
+        ("hooks", local.hooks.is_some()),
+        ("script", local.script.is_some()),
+        ("script_timeout", local.script_timeout.is_some()),
+        ("database", local.database.is_some()),
+    ];
+    for (name, _) in global_only.iter().filter(|(_, set)| *set) {
+        log::warn!(
+            "`{}` can only be set in the main configuration file, not in `{:?}`. Not using it",
+            name,
+            file
+        );
+    }
+
+    // Codes and routes are added to the ones above
+    if let Some(codes) = local.codes {
+        config.codes.extend(codes);
+    }
+    if let Some(mime_routes) = local.mime_routes {
+        config.mime_routes.extend(mime_routes);
+    }
+
+    // The destination is inside the one above, so that files stay inside the
+    // main destination. It's created when files are moved there
+    if let Some(dest) = local.dest {
+        let dest = match shellexpand::full(&dest.to_string_lossy()) {
+            Ok(dest) => PathBuf::from(dest.as_ref()),
+            Err(e) => {
+                log::error!("Can't expand the destination of `{:?}` : {}", file, e);
+                return None;
+            }
+        };
+
+        if !dest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            log::error!(
+                "The destination of `{:?}` must be relative to the one above, without `..`. Not using it",
+                file
+            );
+            return None;
+        }
+        config.dest = config.dest.join(dest);
+    }
+
+    macro_rules! replace {
+        ($($field:ident),+) => {
+            $(if let Some(value) = local.$field {
+                config.$field = value;
+            })+
+        };
+    }
+    replace!(
+        timeinfo,
+        separator,
+        detect_extensions,
+        extensions,
+        begin_var,
+        end_var,
+        last_token,
+        escape,
+        case_insensitive_codes,
+        dest_case,
+        sanitize,
+        unicode_normalization,
+        ascii,
+        duplicates
+    );
+    if local.filename_separators.is_some() {
+        config.filename_separators = local.filename_separators;
+    }
+
+    if config.clean_files(true) {
+        log::error!(
+            "`{:?}` makes the configuration unusable. Not using it",
+            file
+        );
+        return None;
+    }
+
+    Some(config)
+}
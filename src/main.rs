@@ -3,7 +3,8 @@ use structopt::StructOpt;
 
 use std::process::exit;
 
-use fcs::{conf, run};
+use fcs::conf::cli::Command;
+use fcs::{conf, database, dedupe, run};
 
 fn main() {
     // Getting CLI args
@@ -16,16 +17,18 @@ fn main() {
     match init_with_level(verbose.unwrap_or(log::Level::Error)) {
         Ok(_) => (),
         Err(e) => {
-            println!(
-                "Error happenned while setting up logger : {}. Exiting",
-                e.to_string()
-            );
+            println!("Error happenned while setting up logger : {}. Exiting", e);
             exit(exitcode::DATAERR);
         }
     }
 
     log::trace!("Setting up human panic");
-    human_panic::setup_panic!();
+    #[allow(deprecated)]
+    {
+        human_panic::setup_panic!();
+    }
+
+    let command = args.command.clone();
 
     log::trace!("Setting up the configuration");
     let (my_config, config_file, declared, fatal) = conf::lib::Config::from_args_and_file(args);
@@ -35,6 +38,45 @@ fn main() {
         exit(exitcode::DATAERR);
     }
 
-    log::trace!("Ready to do the dirty job ! Configuration is ready");
-    run::run(my_config, declared, config_file);
+    match command {
+        Some(Command::Dedupe) => {
+            let ignored = my_config
+                .database_path()
+                .map_or(vec![], |path| database::files(&path));
+            dedupe::report(&my_config.dest, &ignored)
+        }
+        Some(Command::Search {
+            code,
+            meaning,
+            name,
+            since,
+            until,
+        }) => {
+            let path = match my_config.database_path() {
+                Some(path) if path.is_file() => path,
+                _ => {
+                    log::error!("No database set up, or it doesn't exist yet. Exiting");
+                    exit(exitcode::DATAERR);
+                }
+            };
+
+            let query = database::Query {
+                code,
+                meaning,
+                name,
+                since: since.and_then(database::day_start),
+                until: until
+                    .and_then(|until| until.succ_opt())
+                    .and_then(database::day_start),
+            };
+            if let Err(e) = database::report(&path, &query) {
+                log::error!("Can't search database `{:?}` : {}", path, e);
+                exit(exitcode::DATAERR);
+            }
+        }
+        None => {
+            log::trace!("Ready to do the dirty job ! Configuration is ready");
+            run::run(my_config, declared, config_file);
+        }
+    }
 }
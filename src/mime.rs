@@ -0,0 +1,31 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// The MIME type of the file, found from its first bytes rather than from its
+// extension, which is often wrong
+pub fn sniff(path: &Path) -> Option<&'static str> {
+    match infer::get_from_path(path) {
+        Ok(kind) => kind.map(|kind| kind.mime_type()),
+        Err(e) => {
+            log::debug!("Can't read `{:?}` to find its type : {}", path, e);
+            None
+        }
+    }
+}
+
+// Where files of type `mime` go. `image/png` wins over `image/*`, which wins
+// over `*`
+pub fn route<'a>(routes: &'a HashMap<String, PathBuf>, mime: &str) -> Option<&'a Path> {
+    let mime = mime.to_lowercase();
+    let top_level = mime.split('/').next().unwrap_or_default();
+
+    [
+        mime.clone(),
+        format!("{}/*", top_level),
+        "*".to_owned(),
+        "*/*".to_owned(),
+    ]
+    .iter()
+    .find_map(|key| routes.get(key))
+    .map(PathBuf::as_path)
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+use std::sync::Mutex;
+use std::time;
+
+use crate::conf::lib::Config;
+
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    // When the directory became empty
+    since: time::SystemTime,
+    // When we last modified the directory, to tell our changes apart from others'
+    touched: time::SystemTime,
+    // When the directory was last modified before we moved files out of it
+    changed: time::SystemTime,
+}
+
+// When the directory of `file` was last modified
+pub fn changed(file: &path::Path) -> Option<time::SystemTime> {
+    modified(file.parent()?)
+}
+
+fn modified(dir: &path::Path) -> Option<time::SystemTime> {
+    fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Directories files were moved out of, which are removed once they have
+// stayed empty for long enough
+#[derive(Debug, Default)]
+pub struct Pruner {
+    candidates: Mutex<HashMap<path::PathBuf, Candidate>>,
+}
+
+impl Pruner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `changed` is when the directory of `file` was last modified before the
+    // file was moved out of it
+    pub fn moved_from(&self, file: &path::Path, changed: Option<time::SystemTime>) {
+        if let Some(dir) = file.parent() {
+            let now = time::SystemTime::now();
+            let mut candidates = self.candidates.lock().unwrap();
+            // Files moved before from the same directory changed it too
+            let changed = match candidates.get(dir) {
+                Some(candidate) => candidate.changed,
+                None => changed.unwrap_or(now),
+            };
+            candidates.insert(
+                dir.to_owned(),
+                Candidate {
+                    since: now,
+                    touched: now,
+                    changed,
+                },
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.lock().unwrap().is_empty()
+    }
+
+    pub fn prune(&self, config: &Config) {
+        self.prune_since(config, false);
+    }
+
+    // When there won't be any next loop to check whether directories stayed
+    // empty : they're removed if nothing but fcs modified them for long enough
+    pub fn prune_once(&self, config: &Config) {
+        self.prune_since(config, true);
+    }
+
+    fn prune_since(&self, config: &Config, once: bool) {
+        let grace = time::Duration::from_millis(config.prune_grace as u64);
+        let mut candidates = self.candidates.lock().unwrap();
+        let mut queue: Vec<_> = candidates.drain().collect();
+
+        while let Some((dir, mut candidate)) = queue.pop() {
+            // Watching directories themselves, and whatever contains the destination, are kept
+            let watched = config
+                .dirs
+                .iter()
+                .any(|root| dir.starts_with(root) && &dir != root);
+            if !watched || config.dest.starts_with(&dir) {
+                continue;
+            }
+
+            let is_empty = match fs::read_dir(&dir) {
+                Ok(mut entries) => entries.next().is_none(),
+                Err(_) => false,
+            };
+            if !is_empty {
+                continue;
+            }
+
+            // Something else happened in the directory since it was emptied
+            if let Some(modified) = modified(&dir) {
+                if modified > candidate.touched {
+                    candidate.since = modified;
+                    candidate.touched = modified;
+                    candidate.changed = modified;
+                }
+            }
+
+            let since = if once {
+                candidate.changed
+            } else {
+                candidate.since
+            };
+            if since.elapsed().unwrap_or_default() < grace {
+                candidates.insert(dir, candidate);
+                continue;
+            }
+
+            let parent = dir.parent().map(|parent| (parent, modified(parent)));
+            match fs::remove_dir(&dir) {
+                Ok(_) => {
+                    log::info!("Removed empty directory {:?}", dir);
+
+                    if let Some((parent, changed)) = parent {
+                        candidate.touched = time::SystemTime::now();
+                        candidate.changed = changed.unwrap_or(candidate.touched);
+                        queue.push((parent.to_owned(), candidate));
+                    }
+                }
+                Err(e) => log::warn!("Couldn't remove empty directory {:?} : {}", dir, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+// Where a code is being expanded
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SegmentContext<'a> {
+    // Position of the part of the file name being expanded
+    pub index: usize,
+    // Previous parts of the file name, as written
+    pub codes: &'a [String],
+    // Previous parts of the file name, once expanded
+    pub segments: &'a [String],
+    // The code each previous part was looked up with, like `mt` for both
+    // `mt` and `{mt|Misc}`, or the part as written when it has none
+    pub resolved: &'a [String],
+}
+
+// Expands codes into their meaning
+pub trait Resolver {
+    // Returns None if the code doesn't mean anything
+    fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String>;
+}
+
+impl<S: BuildHasher> Resolver for HashMap<String, String, S> {
+    #[inline]
+    fn resolve(&self, code: &str, _ctx: &SegmentContext) -> Option<String> {
+        self.get(code).cloned()
+    }
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str, &SegmentContext) -> Option<String>,
+{
+    #[inline]
+    fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String> {
+        self(code, ctx)
+    }
+}
+
+// A code's meaning, other codes meaning the same, and the codes meaning
+// something else after it, like `alg` meaning Algebra after `mt` but
+// Algorithms after `cs`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Code {
+    Meaning(String),
+    Entry {
+        name: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        aliases: Vec<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        codes: HashMap<String, Code>,
+    },
+}
+
+impl Code {
+    pub fn meaning(&self) -> &str {
+        match self {
+            Self::Meaning(meaning) => meaning,
+            Self::Entry { name, .. } => name,
+        }
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        match self {
+            Self::Meaning(_) => &[],
+            Self::Entry { aliases, .. } => aliases,
+        }
+    }
+
+    pub fn codes(&self) -> Option<&HashMap<String, Code>> {
+        match self {
+            Self::Meaning(_) => None,
+            Self::Entry { codes, .. } => Some(codes),
+        }
+    }
+}
+
+impl From<&str> for Code {
+    fn from(meaning: &str) -> Self {
+        Self::Meaning(meaning.to_owned())
+    }
+}
+
+impl From<String> for Code {
+    fn from(meaning: String) -> Self {
+        Self::Meaning(meaning)
+    }
+}
+
+// Each part of the file name is looked up in the namespace of the previous
+// one, if it has one, then in the global codes
+impl<S: BuildHasher> Resolver for HashMap<String, Code, S> {
+    fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String> {
+        let mut namespace: Option<&HashMap<String, Code>> = None;
+        for previous in ctx.resolved {
+            namespace = namespace
+                .and_then(|namespace| namespace.get(previous))
+                .or_else(|| self.get(previous))
+                .and_then(Code::codes);
+        }
+
+        namespace
+            .and_then(|namespace| namespace.get(code))
+            .or_else(|| self.get(code))
+            .map(|code| code.meaning().to_owned())
+    }
+}
+
+// Looks codes up whatever their case, in codes that are all lowercase, like
+// the ones of the configuration with `case_insensitive_codes`
+pub struct CaseInsensitive<'a>(pub &'a dyn Resolver);
+
+impl Resolver for CaseInsensitive<'_> {
+    fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String> {
+        let lowercase = |codes: &[String]| -> Vec<String> {
+            codes.iter().map(|code| code.to_lowercase()).collect()
+        };
+        let (codes, resolved) = (lowercase(ctx.codes), lowercase(ctx.resolved));
+        let ctx = SegmentContext {
+            codes: &codes,
+            resolved: &resolved,
+            ..*ctx
+        };
+
+        self.0.resolve(&code.to_lowercase(), &ctx)
+    }
+}
+
+// Tries each resolver in turn, the first one knowing the code wins
+#[derive(Default)]
+pub struct Chain {
+    resolvers: Vec<Box<dyn Resolver + Send + Sync>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, resolver: impl Resolver + Send + Sync + 'static) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+}
+
+impl Resolver for Chain {
+    fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String> {
+        self.resolvers
+            .iter()
+            .find_map(|resolver| resolver.resolve(code, ctx))
+    }
+}
@@ -1,162 +1,331 @@
-use chrono::{offset::TimeZone, Local, NaiveDateTime};
+use chrono::{offset::TimeZone, DateTime, Local};
 use locale::Time;
 use rayon::prelude::*;
-use scan_dir::ScanDir;
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread::sleep;
 use std::time;
 
+use crate::case;
 use crate::conf::lib;
 use crate::conf::lib::{Config, DeclaredType};
-
+use crate::confine;
+use crate::database::{self, Database};
+use crate::dedupe::{self, Duplicates};
+use crate::hooks;
+use crate::local;
+use crate::mime;
+use crate::prune;
+use crate::resolve::{CaseInsensitive, Code, Resolver, SegmentContext};
+use crate::script::{FileScope, Script};
+use crate::stable;
+use crate::syntax::{self, Node, Span, Syntax, TokenKind};
+use crate::transform;
+use crate::walk;
+
+// What a run of `count` last tokens stands for : the `count`-th code used in
+// the file name. Runs longer than the history chain several codes
 #[inline]
-pub fn expand_last(code: &String, last: &Vec<String>, last_token: char) -> String {
-    let mut length = last.len();
-    let mut code_tmp = code.to_owned();
-    let mut result: String = code.to_owned();
+pub fn expand_last(count: usize, last: &[String]) -> Option<String> {
+    if last.is_empty() {
+        return None;
+    }
 
-    while length > 0 {
-        result = "".to_string();
-        let scheme_to_find: String = (0..length).map(|_| last_token).collect();
+    let mut result = String::new();
+    let mut count = count;
+    while count > 0 {
+        let length = count.min(last.len());
+        result.push_str(&last[length - 1]);
+        count -= length;
+    }
 
-        while let Some(scheme_index) = code_tmp.find(&scheme_to_find) {
-            result.push_str(&code_tmp[..scheme_index]);
+    Some(result)
+}
 
-            result.push_str(&last[length - 1]);
+// Separates a code from what to use when it doesn't mean anything, like `{xyz|Misc}`
+pub const FALLBACK_SEPARATOR: char = '|';
+// Fallback failing the file instead, like `{xyz|?}`
+pub const FALLBACK_FAIL: &str = "?";
 
-            code_tmp = code_tmp[scheme_index + length - 1..].to_string();
+// Marks a back-reference giving a part as it was written, like `{raw:,-1}`
+pub const RAW_PREFIX: &str = "raw:";
 
-            if code_tmp.len() == 0 {
-                break;
-            }
+// Returns None if neither the code, nor the code a function applies to, mean anything
+fn lookup(code: &str, codes: &dyn Resolver, ctx: &SegmentContext) -> Option<String> {
+    codes.resolve(code, ctx).or_else(|| {
+        let (transform, value) = transform::parse(code)?;
+        Some(transform.apply(&lookup(value, codes, ctx)?))
+    })
+}
 
-            code_tmp = code_tmp[1..].to_string();
+// The code `code` means something through : itself, the code before its
+// fallback, or the one a function applies to
+fn resolved_code(code: &str, codes: &dyn Resolver, ctx: &SegmentContext) -> Option<String> {
+    if codes.resolve(code, ctx).is_some() {
+        return Some(code.to_owned());
+    }
+    if let Some((name, _)) = code.split_once(FALLBACK_SEPARATOR) {
+        if codes.resolve(name, ctx).is_some() {
+            return Some(name.to_owned());
         }
-
-        result.push_str(&code_tmp);
-        code_tmp = result.clone();
-
-        length -= 1;
     }
 
-    result
+    let (_, value) = transform::parse(code)?;
+    resolved_code(value, codes, ctx)
 }
 
-#[inline]
-pub fn decode(code: &String, codes: &HashMap<String, String>) -> String {
-    codes.get(code).unwrap_or(code).to_owned()
+// Codes that don't mean anything are used plain
+fn decode_plain(code: &str, codes: &dyn Resolver, ctx: &SegmentContext) -> String {
+    if let Some(meaning) = codes.resolve(code, ctx) {
+        return meaning;
+    }
+
+    // The value a function applies to is decoded too, so `{upper:fr}` gives `FRENCH`
+    match transform::parse(code) {
+        Some((transform, value)) => transform.apply(&decode_plain(value, codes, ctx)),
+        None => code.to_owned(),
+    }
 }
 
 #[inline]
-pub fn find_first_valid_opening_bracket(
-    input: &str,
-    begin_var: char,
-    end_var: char,
-) -> Option<usize> {
-    let result: Option<usize>;
-    let mut offset = 0;
-    let mut mut_input = input;
-
-    loop {
-        match mut_input.find(begin_var) {
-            Some(naive_first) => match mut_input[naive_first + 1..].find(end_var) {
-                Some(naive_first_closing_after_first) => match mut_input[naive_first + 1..]
-                    .find(begin_var)
-                {
-                    Some(naive_next) => {
-                        let naive_next = naive_next + naive_first + 1;
-                        if naive_first < naive_next && naive_next < naive_first_closing_after_first
-                        {
-                            offset += naive_next;
-                            mut_input = &mut_input[naive_next..];
-                            continue;
-                        } else {
-                            result = Some(naive_first + offset);
-                            break;
-                        }
-                    }
-                    None => {
-                        result = Some(naive_first + offset);
-                        break;
-                    }
-                },
-                None => {
-                    result = None;
-                    break;
+pub fn decode(code: &str, codes: &dyn Resolver, ctx: &SegmentContext) -> Result<String, String> {
+    // Codes containing the separator keep the priority
+    match code.split_once(FALLBACK_SEPARATOR) {
+        Some((name, fallback)) if codes.resolve(code, ctx).is_none() => {
+            match lookup(name, codes, ctx) {
+                Some(meaning) => Ok(meaning),
+                None if fallback == FALLBACK_FAIL => {
+                    Err(format!("Code `{}` doesn't mean anything", name))
                 }
-            },
-            None => {
-                result = None;
-                break;
+                None => Ok(fallback.to_owned()),
             }
         }
+        _ => Ok(decode_plain(code, codes, ctx)),
     }
+}
 
-    result
+// Like `decode`, but codes that don't mean anything are errors
+pub fn decode_strict(
+    code: &str,
+    codes: &dyn Resolver,
+    ctx: &SegmentContext,
+) -> Result<String, String> {
+    match code.split_once(FALLBACK_SEPARATOR) {
+        Some(_) if codes.resolve(code, ctx).is_none() => decode(code, codes, ctx),
+        _ => {
+            lookup(code, codes, ctx).ok_or_else(|| format!("Code `{}` doesn't mean anything", code))
+        }
+    }
 }
 
-// fvop stands for First Valid Opening Bracket
-#[inline]
-pub fn expand(
-    input: &str,
-    codes: &HashMap<String, String>,
-    begin_var: char,
-    end_var: char,
-    fvob: Option<usize>,
-    last: &mut Vec<String>,
-    last_token: char,
-) -> String {
-    if let Some(mut next_seq_beg) =
-        fvob.or_else(|| find_first_valid_opening_bracket(input, begin_var, end_var))
-    {
-        let mut result = String::with_capacity(input.len());
+// Expands the variables of one part of a file name
+struct Expander<'a> {
+    codes: &'a dyn Resolver,
+    ctx: &'a SegmentContext<'a>,
+    // Codes used so far, for last tokens
+    last: &'a mut Vec<String>,
+    // Whether codes that don't mean anything are errors
+    strict: bool,
+    // The code the last variable expanded was looked up with
+    resolved: Option<String>,
+}
 
-        let mut input_str = input;
+impl Expander<'_> {
+    // `{,3}` is the third part of the file name once expanded, `{,-1}` the
+    // previous one, and `{raw:,-1}` the previous one as it was written.
+    // Returns None if the variable isn't a back-reference
+    fn back_reference(
+        &mut self,
+        children: &[Node],
+        span: Span,
+    ) -> Option<Result<String, syntax::Error>> {
+        let (raw, number) = match children {
+            [Node::Last(1, ..), Node::Text(number, _)] => (false, number),
+            [Node::Text(prefix, _), Node::Last(1, ..), Node::Text(number, _)]
+                if prefix == RAW_PREFIX =>
+            {
+                (true, number)
+            }
+            _ => return None,
+        };
+        let number: isize = number.parse().ok()?;
 
-        loop {
-            result.push_str(&input_str[..next_seq_beg]);
+        let count = self.ctx.segments.len() as isize;
+        let index = if number > 0 {
+            number - 1
+        } else {
+            count + number
+        };
+        if number == 0 || index < 0 || index >= count {
+            return Some(Err(syntax::Error {
+                message: format!("There's no part {} before this one", number),
+                span,
+            }));
+        }
 
-            input_str = &input_str[next_seq_beg..];
-            if input_str.is_empty() {
-                break;
-            }
+        let index = index as usize;
+        let code = self.ctx.codes[index].clone();
+        self.last.push(code.clone());
+        self.resolved = self.ctx.resolved.get(index).cloned();
 
-            next_seq_beg = match input_str.find(end_var) {
-                Some(res) => {
-                    let code = &input_str[1..res].to_string();
-                    let code = expand_last(code, last, last_token);
-                    result.push_str(&decode(&code, codes));
-                    last.push(code.clone());
-                    res + 1
+        Some(Ok(if raw {
+            code
+        } else {
+            self.ctx.segments[index].clone()
+        }))
+    }
+
+    fn variable(&mut self, children: &[Node], span: Span) -> Result<String, syntax::Error> {
+        if let Some(result) = self.back_reference(children, span) {
+            return result;
+        }
+
+        // Variables inside are expanded first, so that last tokens can refer to them
+        let mut values = vec![];
+        for child in children {
+            if let Node::Variable(children, span) = child {
+                values.push(self.variable(children, *span)?);
+            }
+        }
+        let mut values = values.into_iter();
+
+        let mut code = String::new();
+        for child in children {
+            match child {
+                Node::Text(text, _) => code.push_str(text),
+                Node::Last(count, text, _) => {
+                    code.push_str(&expand_last(*count, self.last).unwrap_or_else(|| text.clone()))
                 }
-                None => next_seq_beg + 1,
+                Node::Variable(..) => code.push_str(&values.next().unwrap_or_default()),
+            }
+        }
+
+        let meaning = if self.strict {
+            decode_strict(&code, self.codes, self.ctx)
+        } else {
+            decode(&code, self.codes, self.ctx)
+        }
+        .map_err(|message| syntax::Error { message, span })?;
+        self.resolved = resolved_code(&code, self.codes, self.ctx);
+        self.last.push(code);
+
+        Ok(meaning)
+    }
+
+    // Last tokens outside of variables are plain text
+    fn text(&mut self, nodes: &[Node]) -> Result<String, syntax::Error> {
+        let mut result = String::new();
+
+        for node in nodes {
+            match node {
+                Node::Text(text, _) => result.push_str(text),
+                Node::Last(_, text, _) => result.push_str(text),
+                Node::Variable(children, span) => result.push_str(&self.variable(children, *span)?),
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Returns the expanded part, what last tokens will refer to, and the code
+    // it was looked up with
+    fn segment(
+        &mut self,
+        nodes: &[Node],
+    ) -> Result<(String, String, Option<String>), syntax::Error> {
+        if nodes.iter().any(|node| matches!(node, Node::Variable(..))) {
+            let segment = self.text(nodes)?;
+            // Only a part which is a single variable was looked up with a code
+            let resolved = match nodes {
+                [Node::Variable(..)] => self.resolved.take(),
+                _ => None,
             };
+            return Ok((segment.clone(), segment, resolved));
+        }
 
-            input_str = &input_str[next_seq_beg..];
-            next_seq_beg = find_first_valid_opening_bracket(input_str, begin_var, end_var)
-                .unwrap_or(input_str.len());
+        // Without variables, the whole part is a code
+        let mut code = String::new();
+        for node in nodes {
+            match node {
+                Node::Last(count, text, _) => {
+                    code.push_str(&expand_last(*count, self.last).unwrap_or_else(|| text.clone()))
+                }
+                Node::Text(text, _) => code.push_str(text),
+                Node::Variable(..) => (),
+            }
         }
-        result
-    } else {
-        input.to_owned()
+
+        // Functions and fallbacks only apply inside variables
+        let meaning = self
+            .codes
+            .resolve(&code, self.ctx)
+            .unwrap_or_else(|| code.clone());
+
+        Ok((meaning, code.clone(), Some(code)))
     }
 }
 
+// Expands every variable of `input`
+pub fn expand(
+    input: &str,
+    codes: &dyn Resolver,
+    ctx: &SegmentContext,
+    syntax: &Syntax,
+    last: &mut Vec<String>,
+) -> Result<String, syntax::Error> {
+    let nodes = syntax::parse(input, &syntax::tokenize(input, syntax));
+
+    Expander {
+        codes,
+        ctx,
+        last,
+        strict: false,
+        resolved: None,
+    }
+    .text(&nodes)
+}
+
+// Like `expand`, but variables must all mean something
+pub fn expand_strict(
+    input: &str,
+    codes: &dyn Resolver,
+    syntax: &Syntax,
+) -> Result<String, syntax::Error> {
+    let nodes = syntax::parse(input, &syntax::tokenize(input, syntax));
+
+    Expander {
+        codes,
+        ctx: &SegmentContext::default(),
+        last: &mut vec![],
+        strict: true,
+        resolved: None,
+    }
+    .text(&nodes)
+}
+
+// Where a file goes, and how its name was expanded to get there
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewName {
+    pub file: path::PathBuf,
+    pub dir: path::PathBuf,
+    // Each part of the file name, as it was written
+    pub codes: Vec<String>,
+    // Each part of the file name, once expanded
+    pub segments: Vec<String>,
+}
+
 pub fn get_new_name(
     name: &str,
     dest: &path::Path,
-    codes: &HashMap<String, String>,
+    codes: &dyn Resolver,
     timestamp: Option<time::SystemTime>,
-    separator: (char, usize),
-    var: (char, char),
-    last_token: char,
-) -> Result<(path::PathBuf, path::PathBuf), Box<dyn Error>> {
+    syntax: &Syntax,
+) -> Result<NewName, Box<dyn Error>> {
     let mut year: String = "".to_string();
     let month_nb: usize;
     let mut month_str: String = "".to_string();
@@ -169,15 +338,15 @@ pub fn get_new_name(
             .as_secs();
 
         let datetime = Local
-            .from_utc_datetime(&NaiveDateTime::from_timestamp(timestamp as i64, 0))
+            .from_utc_datetime(
+                &DateTime::from_timestamp(timestamp as i64, 0)
+                    .ok_or("Timestamp out of range")?
+                    .naive_utc(),
+            )
             .format("%Y %m")
             .to_string();
 
-        year = datetime
-            .chars()
-            .skip(0)
-            .take(datetime.find(' ').unwrap())
-            .collect();
+        year = datetime.chars().take(datetime.find(' ').unwrap()).collect();
         month_nb = datetime
             .chars()
             .skip(datetime.find(' ').unwrap() + 1)
@@ -192,44 +361,76 @@ pub fn get_new_name(
         }
     }
 
+    // Codes are normalized like file names, so that they're found whatever
+    // the system the file comes from
+    let name = &*syntax.unicode_normalization.apply(name);
+
     let mut ending_path: path::PathBuf = path::PathBuf::new();
     ending_path.push(dest);
     if timeinfo {
         ending_path.push(year);
     }
 
-    let mut next: &str = name;
-    let mut splitted: (&str, &str) = ("", "");
+    let tokens = syntax::tokenize(name, syntax);
+    let separators = tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Separator)
+        .count();
+    let filename_separators = syntax.file_name_separators(name, &tokens);
+
+    let mut tokens = tokens.as_slice();
     let mut last = vec![];
-    let mut current: String;
-    while next.matches(separator.0).count() > separator.1 {
-        splitted = next.split_at(next.find(separator.0).unwrap() + 1);
-        let current_str = splitted.0;
-        current = current_str[..current_str.len() - 1].to_string();
-        next = splitted.1;
-
-        let mut should_be_decoded = true;
-
-        while let Some(fvob) = find_first_valid_opening_bracket(&current, var.0, var.1) {
-            current = expand(
-                &current,
-                codes,
-                var.0,
-                var.1,
-                Some(fvob),
-                &mut last,
-                last_token,
-            );
-            should_be_decoded = false;
+    let mut codes_written = vec![];
+    let mut resolved = vec![];
+    let mut segments = vec![];
+    for _ in 0..separators.saturating_sub(filename_separators) {
+        let end = tokens
+            .iter()
+            .position(|token| token.kind == TokenKind::Separator)
+            .unwrap();
+        let part = &tokens[..end];
+        let span = Span {
+            start: part.first().unwrap_or(&tokens[end]).span.start,
+            end: tokens[end].span.start,
+        };
+        tokens = &tokens[end + 1..];
+
+        let ctx = SegmentContext {
+            index: segments.len(),
+            codes: &codes_written,
+            segments: &segments,
+            resolved: &resolved,
+        };
+        let (segment, code, code_resolved) = Expander {
+            codes,
+            ctx: &ctx,
+            last: &mut last,
+            strict: false,
+            resolved: None,
         }
+        .segment(&syntax::parse(name, part))
+        .map_err(|e| e.display_in(name))?;
 
-        if should_be_decoded {
-            ending_path.push(decode(&expand_last(&current, &last, last_token), codes));
+        // Meanings can be several directories, or none at all
+        let segment = if segment.is_empty() {
+            segment
         } else {
-            ending_path.push(current.clone());
-        }
-
-        last.push(current);
+            let mut components = vec![];
+            for component in segment.split('/') {
+                let component = syntax.output(component, false);
+                confine::check_component(&component)
+                    .map_err(|message| syntax::Error { message, span }.display_in(name))?;
+                ending_path.push(&component);
+                components.push(component);
+            }
+            components.join("/")
+        };
+        let written = name[span.start..span.end].to_string();
+        resolved.push(code_resolved.unwrap_or_else(|| written.clone()));
+        codes_written.push(written);
+        segments.push(segment);
+
+        last.push(code);
     }
 
     if timeinfo {
@@ -237,78 +438,488 @@ pub fn get_new_name(
     }
 
     let dir = ending_path.clone();
-    ending_path.push(splitted.1);
+    let file_name: String = tokens.iter().map(|token| token.literal(name)).collect();
+    let file_name = syntax.output(&file_name, true);
+    confine::check_component(&file_name)?;
+    ending_path.push(file_name);
+
+    Ok(NewName {
+        file: ending_path,
+        dir,
+        codes: codes_written,
+        segments,
+    })
+}
 
-    Ok((ending_path, dir))
+// Whether the file name has parts to expand, besides the file's own name
+pub fn has_codes(name: &str, syntax: &Syntax) -> bool {
+    let tokens = syntax::tokenize(name, syntax);
+    tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Separator)
+        .count()
+        > syntax.file_name_separators(name, &tokens)
 }
 
-fn handle(
-    name: &path::Path,
+// Whether the file could be moved : files without codes can still be sent
+// somewhere by the script or by their type
+pub fn wanted(name: &str, config: &Config, script: Option<&Script>) -> bool {
+    has_codes(name, &config.syntax())
+        || !config.mime_routes.is_empty()
+        || script.is_some_and(Script::can_classify)
+}
+
+// Where a file without codes goes, following the type of its contents
+fn route(source: &path::Path, name: &str, config: &Config) -> Option<NewName> {
+    // Don't read the file when there is nothing to route it with
+    if config.mime_routes.is_empty() {
+        return None;
+    }
+    let mime = mime::sniff(source)?;
+    let route = match mime::route(&config.mime_routes, mime) {
+        Some(route) => route,
+        None => {
+            log::debug!("No route for file {:?}, of type `{}`", source, mime);
+            return None;
+        }
+    };
+
+    let syntax = config.syntax();
+    let segments: Vec<String> = route
+        .iter()
+        .map(|segment| syntax.output(&segment.to_string_lossy(), false))
+        .collect();
+    let dir = segments
+        .iter()
+        .fold(config.dest.clone(), |dir, segment| dir.join(segment));
+
+    Some(NewName {
+        file: dir.join(syntax.output(name, true)),
+        dir,
+        codes: vec![],
+        segments,
+    })
+}
+
+// Where the script's `classify` function sends a file, if it decides for it
+fn classify(
+    name: &str,
+    scope: &FileScope,
     dest: &path::Path,
-    codes: &HashMap<String, String>,
-    timeinfo: bool,
-    separator: (char, usize),
-    var: (char, char),
-    last_token: char,
-) {
+) -> Result<Option<NewName>, Box<dyn Error>> {
+    if !scope.script.can_classify() {
+        return Ok(None);
+    }
+
+    let target = match scope.script.classify(name, &scope.meta)? {
+        Some(target) => hooks::validate_target(path::Path::new(&target), dest)?,
+        None => return Ok(None),
+    };
+
+    let dir = target.parent().unwrap().to_owned();
+    let segments = dir
+        .strip_prefix(dest)?
+        .iter()
+        .map(|segment| segment.to_string_lossy().into_owned())
+        .collect();
+
+    Ok(Some(NewName {
+        file: target,
+        dir,
+        codes: vec![],
+        segments,
+    }))
+}
+
+// Whether `path`, with its symlinks resolved, is inside one of `dirs`
+pub fn watched(path: &path::Path, dirs: &HashSet<path::PathBuf>) -> bool {
+    dirs.iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir))
+}
+
+// Moves a duplicate to `target` as a hard link to the file it's identical
+// to, or moves it as usual if they aren't on the same filesystem
+fn link(existing: &path::Path, source: &path::Path, target: &path::Path) -> io::Result<()> {
+    fs::hard_link(existing, target)
+        .and_then(|_| fs::remove_file(source))
+        .or_else(|e| {
+            log::warn!(
+                "Can't link {:?} to {:?} : {}. Moving it instead",
+                target,
+                existing,
+                e
+            );
+            fs::rename(source, target)
+        })
+}
+
+// Returns whether the file was moved, or deleted as a duplicate
+pub fn handle(
+    name: &path::Path,
+    config: &Config,
+    runner: &hooks::Runner,
+    script: Option<&Script>,
+    index: &dedupe::Index,
+    database: Option<&Database>,
+) -> bool {
     if !path::Path::new(name.to_str().unwrap()).exists() {
         log::warn!(
             "File `{:#}` disappeared before I could handle it !",
             name.to_str().unwrap_or("ERROR WHEN DISPLAYING THE FILE")
         );
-        return;
+        return false;
     }
 
-    let timestamp: Option<time::SystemTime> = if timeinfo {
-        Some(fs::metadata(&name).unwrap().created().unwrap())
+    let timestamp: Option<time::SystemTime> = if config.timeinfo {
+        Some(fs::metadata(name).unwrap().created().unwrap())
     } else {
         None
     };
 
-    match get_new_name(
-        name.file_name().unwrap().to_str().unwrap(),
-        dest,
-        codes,
-        timestamp,
-        separator,
-        var,
-        last_token,
-    ) {
-        Ok(result) => match fs::create_dir_all(&result.1) {
-            Ok(_) => match fs::rename(&name, &result.0) {
-                Ok(_) => log::info!("Moved path from {:?} to {:?}", name, result.0),
+    // The file the symlink points to is moved, rather than the symlink itself.
+    // It must be watched too, otherwise a symlink could move any file
+    let source = match fs::symlink_metadata(name) {
+        Ok(metadata) if config.resolve_symlinks && metadata.file_type().is_symlink() => {
+            match fs::canonicalize(name) {
+                Ok(source) if watched(&source, &config.dirs) => source,
+                Ok(source) => {
+                    log::warn!(
+                        "Symlink `{:?}` points to {:?}, outside of the watching directories. Ignoring it",
+                        name,
+                        source
+                    );
+                    return false;
+                }
                 Err(_) => {
-                    log::warn!("File `{:?}` disappeared before I could handle it !", name)
+                    log::warn!("Symlink `{:?}` is broken !", name);
+                    return false;
+                }
+            }
+        }
+        _ => name.to_owned(),
+    };
+
+    let file_name = name.file_name().unwrap().to_str().unwrap();
+    let scope = script.map(|script| FileScope::new(script, &source));
+
+    let classified = match &scope {
+        Some(scope) => classify(file_name, scope, &config.dest),
+        None => Ok(None),
+    };
+
+    let new_name = match classified {
+        Ok(Some(result)) => Ok(result),
+        Ok(None) if !has_codes(file_name, &config.syntax()) => {
+            match route(&source, file_name, config) {
+                Some(result) => Ok(result),
+                None => return false,
+            }
+        }
+        Ok(None) => {
+            let insensitive = CaseInsensitive(&config.codes);
+            let codes: &dyn Resolver = if config.case_insensitive_codes {
+                &insensitive
+            } else {
+                &config.codes
+            };
+
+            // `{fn:...}` variables are computed by the script, others are codes
+            let resolver = |code: &str, ctx: &SegmentContext| match &scope {
+                Some(scope) if code.starts_with(crate::script::FUNCTION_PREFIX) => {
+                    scope.resolve(code, ctx)
+                }
+                _ => codes.resolve(code, ctx),
+            };
+
+            get_new_name(
+                file_name,
+                &config.dest,
+                &resolver,
+                timestamp,
+                &config.syntax(),
+            )
+            .and_then(|result| {
+                match scope.as_ref().and_then(|scope| scope.error.take()) {
+                    Some(e) => Err(e.into()),
+                    None => Ok(result),
+                }
+            })
+        }
+        Err(e) => Err(e),
+    };
+
+    let mut result = match new_name {
+        Ok(result) => case::normalize(
+            result,
+            &config.dest,
+            config.dest_case,
+            config.unicode_normalization,
+        ),
+        Err(e) => {
+            log::error!("Error happened with file {:?} : {}", name, e);
+            return false;
+        }
+    };
+
+    for command in &config.hooks.pre_move {
+        let env = hooks::move_env(&source, &result, config.separator.first());
+
+        match runner
+            .filter(command, &source, &result, &env, &config.hooks)
+            .and_then(|decision| match decision {
+                hooks::Decision::Rewrite(target) => {
+                    hooks::validate_target(&target, &config.dest).map(hooks::Decision::Rewrite)
                 }
-            },
-            Err(_) => log::warn!("File `{:?}` disappeared before I could handle it !", name),
+                decision => Ok(decision),
+            }) {
+            Ok(hooks::Decision::Accept) => (),
+            Ok(hooks::Decision::Skip) => {
+                log::info!("Hook `{}` skipped file {:?}", command, name);
+                return false;
+            }
+            Ok(hooks::Decision::Rewrite(target)) => {
+                log::debug!("Hook `{}` sent file {:?} to {:?}", command, name, target);
+                result.dir = target.parent().unwrap().to_owned();
+                result.file = target;
+            }
+            Err(e) => {
+                log::error!("Error happened with file {:?} : {}", name, e);
+                return false;
+            }
+        }
+    }
+
+    if let Err(e) = confine::check_inside(&result.file, &config.dest) {
+        log::error!("Error happened with file {:?} : {}", name, e);
+        return false;
+    }
+
+    let (duplicate, hash) = match config.duplicates {
+        Duplicates::Ignore => (None, None),
+        _ => match index.claim(&config.dest, &source, &result.file) {
+            Ok((duplicate, hash)) => (duplicate, Some(hash)),
+            Err(e) => {
+                log::error!("Error happened with file {:?} : {}", name, e);
+                return false;
+            }
         },
+    };
+    if let Some(existing) = &duplicate {
+        if config.duplicates == Duplicates::Delete {
+            // The file found could have been claimed by one which wasn't moved
+            if !existing.exists() {
+                log::warn!(
+                    "File {:?} is identical to {:?}, which isn't there. Trying again later",
+                    source,
+                    existing
+                );
+                return false;
+            }
+            if source != name && !watched(&source, &config.dirs) {
+                log::warn!(
+                    "File {:?} is outside of the watching directories. Not deleting it",
+                    source
+                );
+                return false;
+            }
 
-        Err(e) => log::error!("Error happened with file {:?} : {}", name, e.to_string()),
+            log::info!(
+                "File {:?} is identical to {:?}. Deleting it",
+                source,
+                existing
+            );
+            if let Err(e) = fs::remove_file(&source) {
+                log::error!("Couldn't remove file {:?} : {}", source, e);
+                return false;
+            }
+            if source != name {
+                if let Err(e) = fs::remove_file(name) {
+                    log::warn!("Couldn't remove symlink `{:?}` : {}", name, e);
+                }
+            }
+            return true;
+        }
+
+        if config.duplicates == Duplicates::Keep {
+            log::warn!("File {:?} is identical to {:?}", source, existing);
+        } else {
+            log::info!(
+                "File {:?} is identical to {:?}. Linking it",
+                source,
+                existing
+            );
+        }
     }
+
+    // The file's times and contents are recorded before it's moved
+    let record = database.map(|_| {
+        let metadata = fs::metadata(&source).ok();
+        let time = |time: io::Result<time::SystemTime>| time.ok().and_then(database::timestamp);
+        database::Move {
+            name: file_name.to_owned(),
+            source_dir: name
+                .parent()
+                .and_then(|dir| fs::canonicalize(dir).ok())
+                .unwrap_or_else(|| name.to_owned()),
+            codes: result.codes.clone(),
+            segments: result.segments.clone(),
+            target: result.file.clone(),
+            created: metadata
+                .as_ref()
+                .and_then(|metadata| time(metadata.created())),
+            modified: metadata
+                .as_ref()
+                .and_then(|metadata| time(metadata.modified())),
+            moved: 0,
+            hash: hash
+                .or_else(|| dedupe::hash(&source).ok())
+                .map(|hash| hash.to_hex().to_string()),
+        }
+    });
+
+    let placed = |target: &path::Path| match &duplicate {
+        Some(existing) if config.duplicates == Duplicates::Hardlink => {
+            link(existing, &source, target)
+        }
+        _ => fs::rename(&source, target),
+    };
+
+    match fs::create_dir_all(&result.dir) {
+        Ok(_) => match placed(&result.file) {
+            Ok(_) => {
+                log::info!("Moved path from {:?} to {:?}", source, result.file);
+
+                if let (Some(database), Some(mut record)) = (database, record) {
+                    // Paths are recorded absolute, so that they're found from anywhere
+                    record.target = fs::canonicalize(&record.target).unwrap_or(record.target);
+                    record.moved = database::timestamp(time::SystemTime::now()).unwrap_or(0);
+                    if let Err(e) = database.record(&record) {
+                        log::error!("Couldn't record the move of {:?} : {}", name, e);
+                    }
+                }
+
+                if source != name {
+                    if let Err(e) = fs::remove_file(name) {
+                        log::warn!("Couldn't remove symlink `{:?}` : {}", name, e);
+                    }
+                }
+
+                if !config.hooks.post_move.is_empty() {
+                    let env = hooks::move_env(&source, &result, config.separator.first());
+                    for command in &config.hooks.post_move {
+                        runner.run(command, &env, &config.hooks);
+                    }
+                }
+
+                return true;
+            }
+            Err(_) => {
+                log::warn!("File `{:?}` disappeared before I could handle it !", name)
+            }
+        },
+        Err(_) => log::warn!("File `{:?}` disappeared before I could handle it !", name),
+    }
+
+    if duplicate.is_none() && config.duplicates != Duplicates::Ignore {
+        index.release(&config.dest, &result.file);
+    }
+    false
 }
 
-fn make_tables(codes: &HashMap<String, String>, dest: &str) {
+// One line per code and its aliases, the codes of its namespace being
+// indented below it
+fn render_codes(codes: &HashMap<String, Code>, depth: usize, shortcuts: &mut String) {
+    let aliases: HashSet<&String> = codes.values().flat_map(Code::aliases).collect();
+    let mut keys: Vec<&String> = codes.keys().filter(|key| !aliases.contains(key)).collect();
+    keys.sort();
+
+    for key in keys {
+        let code = &codes[key];
+        let names: Vec<&str> = std::iter::once(key.as_str())
+            .chain(code.aliases().iter().map(String::as_str))
+            .collect();
+        *shortcuts += &format!(
+            "{}{} = {}\n",
+            "\t".repeat(depth),
+            names.join(", "),
+            code.meaning()
+        );
+        if let Some(codes) = code.codes() {
+            render_codes(codes, depth + 1, shortcuts);
+        }
+    }
+}
+
+fn make_tables(codes: &HashMap<String, Code>, dest: &str) {
     if path::Path::new(&format!("{}{}shortcuts", dest, path::MAIN_SEPARATOR)).exists() {
         fs::remove_file(format!("{}{}shortcuts", dest, path::MAIN_SEPARATOR)).unwrap();
     }
 
     if path::Path::new(&format!("{}{}fcs-should_end", dest, path::MAIN_SEPARATOR)).exists() {
-        fs::remove_file(&format!("{}{}fcs-should_end", dest, path::MAIN_SEPARATOR)).unwrap();
+        fs::remove_file(format!("{}{}fcs-should_end", dest, path::MAIN_SEPARATOR)).unwrap();
     }
 
     let mut shortcuts_file =
         fs::File::create(format!("{}{}shortcuts", dest, path::MAIN_SEPARATOR)).unwrap();
 
     let mut shortcuts = String::new();
-    for (key, value) in codes {
-        shortcuts += &format!("\t{} = {}\n", key, value);
-    }
+    render_codes(codes, 1, &mut shortcuts);
 
     shortcuts_file.write_all(shortcuts.as_bytes()).unwrap();
     log::debug!("Codes are : \n{}", shortcuts);
 }
 
+// How often the database forgets the files which disappeared
+const SYNC_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+// Opens the database moves are recorded in, and forgets the files which
+// disappeared from the destination since. Its files are never duplicates
+fn open_database(config: &Config, index: &dedupe::Index) -> Option<Database> {
+    let path = config.database_path()?;
+    index.ignore(database::files(&path));
+
+    match Database::open(&path) {
+        Ok(database) => {
+            sync_database(&database, &config.dest);
+            Some(database)
+        }
+        Err(e) => {
+            log::error!("Can't open database `{:?}` : {}", path, e);
+            None
+        }
+    }
+}
+
+// Forgets the files which disappeared from `dest`. Files of a destination
+// which isn't there, like an unmounted drive, aren't gone
+pub fn sync_database(database: &Database, dest: &path::Path) {
+    if !dest.is_dir() {
+        return;
+    }
+
+    match database.sync() {
+        Ok(0) => (),
+        Ok(gone) => log::info!(
+            "{} files aren't in the destination anymore. Forgot them",
+            gone
+        ),
+        Err(e) => log::error!("Can't forget the files which disappeared : {}", e),
+    }
+}
+
+fn compile_script(config: &Config) -> Option<Script> {
+    let source = config.script.as_ref()?;
+
+    match Script::compile(source, config.script_timeout) {
+        Ok(script) => Some(script),
+        Err(e) => {
+            log::error!("The script can't be compiled : {}", e);
+            None
+        }
+    }
+}
+
 static OPERATING: AtomicBool = AtomicBool::new(false);
 static SHOULD_STOP_PROCESSING: AtomicBool = AtomicBool::new(false);
 
@@ -316,36 +927,58 @@ pub fn run(mut my_config: Config, declared: DeclaredType, mut config_file: Strin
     log::trace!("Creating tables");
     make_tables(&my_config.codes, my_config.dest.to_str().unwrap());
 
-    let handle_for_real_handle = |path: &path::Path, my_config: &lib::Config| -> Result<(), ()> {
+    let tracker = stable::Tracker::new();
+    let pruner = prune::Pruner::new();
+    let runner = hooks::Runner::new();
+    let moved = AtomicUsize::new(0);
+    let index = dedupe::Index::new();
+    let mut script = compile_script(&my_config);
+    let mut database = open_database(&my_config, &index);
+    let mut synced = time::Instant::now();
+    let mut local = local::Local::new();
+
+    // `dest` is the main destination : the one of `my_config` can be a
+    // directory-local one, created when files are moved there
+    let handle_for_real_handle = |path: &path::Path,
+                                  my_config: &lib::Config,
+                                  dest: &path::Path,
+                                  script: Option<&Script>,
+                                  database: Option<&Database>|
+     -> Result<(), ()> {
         if SHOULD_STOP_PROCESSING.load(Ordering::SeqCst) {
             log::trace!("I'm supposed to end while handling files");
             return Err(());
         }
 
-        if !lib::test_path!(&my_config.dest, "dir") {
+        if !lib::test_path!(&dest, "dir") {
             log::error!(
                 "Destination `{:#?}` doesn't exist anymore ! Exiting !",
-                my_config.dest
+                dest
             );
             return Err(());
         }
 
-        handle(
-            path,
-            &my_config.dest,
-            &my_config.codes,
-            my_config.timeinfo,
-            (my_config.separator, my_config.filename_separators),
-            (my_config.begin_var, my_config.end_var),
-            my_config.last_token,
-        );
+        if !tracker.is_stable(path, my_config) {
+            return Ok(());
+        }
+
+        let changed = prune::changed(path);
+        if handle(path, my_config, &runner, script, &index, database) {
+            moved.fetch_add(1, Ordering::SeqCst);
+
+            if my_config.prune_empty_dirs {
+                pruner.moved_from(path, changed);
+            }
+        }
 
         Ok(())
     };
 
     let cleanup = || {
         SHOULD_STOP_PROCESSING.store(true, Ordering::SeqCst);
-        while OPERATING.load(Ordering::SeqCst) {}
+        while OPERATING.load(Ordering::SeqCst) {
+            std::hint::spin_loop();
+        }
 
         log::info!("Goodbye");
         std::process::exit(exitcode::OK);
@@ -366,22 +999,50 @@ pub fn run(mut my_config: Config, declared: DeclaredType, mut config_file: Strin
     log::trace!("Starting my job");
     'outer: loop {
         OPERATING.store(true, Ordering::SeqCst);
+        tracker.refresh(&my_config);
+        index.refresh();
+        moved.store(0, Ordering::SeqCst);
 
+        let mut listed = vec![];
         for dir in &dirs {
             if !lib::test_path!(&dir, "dir") {
                 break;
             }
 
-            let files: Vec<path::PathBuf> = ScanDir::files()
-                .walk(dir, |iter| {
-                    iter.filter(|&(_, ref name)| {
-                        name.matches(my_config.separator).count() > my_config.filename_separators
+            let (max_depth, follow_symlinks) = my_config.scan_options(dir);
+            listed.push(walk::files(dir, max_depth, follow_symlinks));
+        }
+
+        let local_files: Vec<path::PathBuf> = dirs
+            .iter()
+            .zip(&listed)
+            .flat_map(|(dir, files)| local::find(dir, files))
+            .collect();
+        local.update(&local_files, &my_config);
+
+        let listed: Vec<Vec<path::PathBuf>> = listed
+            .into_iter()
+            .map(|files| {
+                files
+                    .into_iter()
+                    .filter(|file| {
+                        let name = file.file_name().unwrap().to_str().unwrap();
+                        wanted(name, local.config(file, &my_config), script.as_ref())
                     })
-                    .map(|(entry, _)| entry.path())
                     .collect()
-                })
-                .unwrap();
+            })
+            .collect();
+
+        // There won't be any next loop to check whether files settled
+        if my_config.once && my_config.settle > 0 && listed.iter().any(|files| !files.is_empty()) {
+            listed
+                .iter()
+                .flatten()
+                .for_each(|file| tracker.observe(file));
+            sleep(time::Duration::from_millis(my_config.settle as u64));
+        }
 
+        for files in listed {
             if !lib::test_path!(&my_config.dest, "dir") {
                 log::error!(
                     "Destination `{:#?}` doesn't exist anymore ! Exiting !",
@@ -392,7 +1053,16 @@ pub fn run(mut my_config: Config, declared: DeclaredType, mut config_file: Strin
 
             let error_hapenned: bool = files
                 .par_iter()
-                .map(|entry| handle_for_real_handle(&entry.to_owned(), &my_config))
+                .map(|entry| {
+                    let config = local.config(entry, &my_config);
+                    handle_for_real_handle(
+                        &entry.to_owned(),
+                        config,
+                        &my_config.dest,
+                        script.as_ref(),
+                        database.as_ref(),
+                    )
+                })
                 .any(|res| res.is_err());
 
             if error_hapenned {
@@ -400,10 +1070,33 @@ pub fn run(mut my_config: Config, declared: DeclaredType, mut config_file: Strin
             }
         }
 
+        if my_config.prune_empty_dirs && !pruner.is_empty() {
+            if my_config.once {
+                pruner.prune_once(&my_config);
+            } else {
+                pruner.prune(&my_config);
+            }
+        }
+
+        let moved_count = moved.load(Ordering::SeqCst);
+        if moved_count > 0 {
+            let env = [("FCS_MOVED".to_string(), moved_count.to_string())];
+            for command in &my_config.hooks.post_loop {
+                runner.run(command, &env, &my_config.hooks);
+            }
+        }
+
         if my_config.once {
             break 'outer;
         }
 
+        if synced.elapsed() >= SYNC_INTERVAL {
+            if let Some(database) = &database {
+                sync_database(database, &my_config.dest);
+            }
+            synced = time::Instant::now();
+        }
+
         // Beyond this, users running with -o won't ever have to suffer the wait
         // of sending info to the other thread or to reload a configuration file,
         // or even worse, just sleeping
@@ -430,6 +1123,10 @@ pub fn run(mut my_config: Config, declared: DeclaredType, mut config_file: Strin
                         }
 
                         make_tables(&my_config.codes, my_config.dest.to_str().unwrap());
+                        script = compile_script(&my_config);
+                        database = open_database(&my_config, &index);
+                        synced = time::Instant::now();
+                        local.clear();
                         old_last_change = new_last_change;
                     };
                 }
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+// Longest directory or file name most filesystems accept, in bytes
+pub const MAX_LENGTH: usize = 255;
+// What characters which can't be used are replaced by
+pub const REPLACEMENT: char = '_';
+
+// Names Windows gives to devices, whatever their case and extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Which filesystems directory and file names must be valid on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    // Names are only truncated
+    Posix,
+    // Valid on NTFS, exFAT and SMB shares too
+    Windows,
+    // Valid almost everywhere, and easy to type in a shell
+    Portable,
+}
+
+impl FromStr for Sanitize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "posix" => Ok(Self::Posix),
+            "windows" => Ok(Self::Windows),
+            "portable" => Ok(Self::Portable),
+            _ => Err(format!("invalid sanitize profile `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Sanitize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Posix => write!(f, "posix"),
+            Self::Windows => write!(f, "windows"),
+            Self::Portable => write!(f, "portable"),
+        }
+    }
+}
+
+impl Sanitize {
+    fn is_forbidden(self, c: char) -> bool {
+        match self {
+            Self::Posix => false,
+            Self::Windows | Self::Portable => c.is_control() || "<>:\"\\|?*".contains(c),
+        }
+    }
+
+    // Makes a directory name, or a file name if `is_file`, valid following
+    // the profile. The same name always gives the same result
+    pub fn apply(self, name: &str, is_file: bool) -> String {
+        let mut result: String = name
+            .chars()
+            .map(|c| if self.is_forbidden(c) { REPLACEMENT } else { c })
+            .collect();
+
+        if self == Self::Portable {
+            result = result
+                .trim_start_matches(|c: char| c == '-' || c.is_whitespace())
+                .to_owned();
+        }
+
+        if self != Self::Posix {
+            trim_end(&mut result, name);
+
+            let stem = result.split('.').next().unwrap_or_default();
+            if RESERVED
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                result.insert(stem.len(), REPLACEMENT);
+            }
+        }
+
+        result = truncate(&result, MAX_LENGTH, is_file);
+
+        if self != Self::Posix {
+            // Cutting the name can leave a trailing dot or space again
+            trim_end(&mut result, name);
+        }
+
+        result
+    }
+}
+
+// Removes trailing dots and spaces, which Windows removes itself so that `a.`
+// and `a` are the same. A name left empty is replaced
+fn trim_end(result: &mut String, name: &str) {
+    result.truncate(result.trim_end_matches(['.', ' ']).len());
+    if result.is_empty() && !name.is_empty() {
+        result.push(REPLACEMENT);
+    }
+}
+
+// Cuts `name` to at most `max` bytes, without splitting a character. File
+// names keep their extension
+pub fn truncate(name: &str, max: usize, is_file: bool) -> String {
+    if name.len() <= max {
+        return name.to_owned();
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if is_file && dot > 0 && name.len() - dot <= max / 2 => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut end = max - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &stem[..end], extension)
+}
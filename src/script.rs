@@ -0,0 +1,200 @@
+use chrono::{DateTime, Datelike, Local};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fs;
+use std::path;
+use std::time;
+
+use crate::resolve::SegmentContext;
+
+// Prefix of the variables calling a function of the script, like `{fn:schoolyear}`
+pub const FUNCTION_PREFIX: &str = "fn:";
+
+thread_local! {
+    // When the script currently running on this thread must be stopped
+    static DEADLINE: Cell<Option<time::Instant>> = const { Cell::new(None) };
+}
+
+// Functions written in the configuration, to compute what codes can't
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    timeout: time::Duration,
+}
+
+impl Script {
+    pub fn compile(source: &str, timeout: usize) -> Result<Self, Box<dyn Error>> {
+        let mut engine = Engine::new();
+
+        // Scripts can't reach anything outside of what they are given, nor
+        // take the whole memory
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_call_levels(64)
+            .set_max_expr_depths(64, 64)
+            .set_max_string_size(1 << 16)
+            .set_max_array_size(1 << 12)
+            .set_max_map_size(1 << 12)
+            .on_print(|text| log::info!("Script : {}", text))
+            .on_debug(|text, _, _| log::debug!("Script : {}", text))
+            .on_progress(|_| {
+                DEADLINE.with(|deadline| match deadline.get() {
+                    Some(deadline) if time::Instant::now() > deadline => Some(Dynamic::UNIT),
+                    _ => None,
+                })
+            });
+
+        let ast = engine.compile(source)?;
+
+        Ok(Self {
+            engine,
+            ast,
+            timeout: time::Duration::from_millis(timeout as u64),
+        })
+    }
+
+    fn arity(&self, function: &str) -> Option<usize> {
+        self.ast
+            .iter_functions()
+            .find(|metadata| metadata.name == function)
+            .map(|metadata| metadata.params.len())
+    }
+
+    fn call(&self, function: &str, args: impl FuncArgs) -> Result<Dynamic, String> {
+        DEADLINE.with(|deadline| deadline.set(Some(time::Instant::now() + self.timeout)));
+
+        let result = self.engine.call_fn_with_options(
+            CallFnOptions::new().eval_ast(false),
+            &mut Scope::new(),
+            &self.ast,
+            function,
+            args,
+        );
+
+        DEADLINE.with(|deadline| deadline.set(None));
+
+        result.map_err(|e| match *e {
+            EvalAltResult::ErrorTerminated(..) => format!(
+                "Script function `{}` ran for more than {} ms",
+                function,
+                self.timeout.as_millis()
+            ),
+            e => format!("Error in script function `{}` : {}", function, e),
+        })
+    }
+
+    pub fn can_classify(&self) -> bool {
+        self.arity("classify") == Some(2)
+    }
+
+    // Calls `classify(name, meta)`, which returns where to move the file
+    // relatively to the destination, or nothing to let codes decide
+    pub fn classify(&self, name: &str, meta: &Map) -> Result<Option<String>, String> {
+        let result = self.call("classify", (name.to_string(), meta.clone()))?;
+
+        if result.is_unit() {
+            Ok(None)
+        } else {
+            Ok(Some(result.to_string()))
+        }
+    }
+
+    // Calls the function named in a `{fn:...}` variable, with or without the file's metadata
+    pub fn expand(
+        &self,
+        function: &str,
+        ctx: &SegmentContext,
+        meta: &Map,
+    ) -> Result<String, String> {
+        let result = match self.arity(function) {
+            Some(0) => self.call(function, ())?,
+            Some(1) => {
+                let mut meta = meta.clone();
+                meta.insert("index".into(), (ctx.index as i64).into());
+                meta.insert("codes".into(), to_array(ctx.codes).into());
+                meta.insert("segments".into(), to_array(ctx.segments).into());
+
+                self.call(function, (meta,))?
+            }
+            Some(_) => {
+                return Err(format!(
+                    "Script function `{}` must take no argument, or the file's metadata",
+                    function
+                ))
+            }
+            None => return Err(format!("Script function `{}` doesn't exist", function)),
+        };
+
+        Ok(result.to_string())
+    }
+}
+
+fn to_array(strings: &[String]) -> Array {
+    strings.iter().map(|string| string.clone().into()).collect()
+}
+
+// What scripts know about a file
+pub fn file_meta(file: &path::Path) -> Map {
+    let mut meta = Map::new();
+
+    if let Some(name) = file.file_name().and_then(|name| name.to_str()) {
+        meta.insert("name".into(), name.to_string().into());
+    }
+
+    if let Ok(metadata) = fs::metadata(file) {
+        meta.insert("size".into(), (metadata.len() as i64).into());
+
+        let timestamp = |time: time::SystemTime| {
+            time.duration_since(time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs() as i64)
+        };
+
+        let modified = metadata.modified().ok();
+        let created = metadata.created().ok().or(modified);
+        if let Some(modified) = modified {
+            meta.insert("modified".into(), timestamp(modified).into());
+        }
+        if let Some(created) = created {
+            meta.insert("created".into(), timestamp(created).into());
+
+            let date: DateTime<Local> = created.into();
+            meta.insert("year".into(), (date.year() as i64).into());
+            meta.insert("month".into(), (date.month() as i64).into());
+            meta.insert("day".into(), (date.day() as i64).into());
+        }
+    }
+
+    meta
+}
+
+// Expands `{fn:...}` variables for one file, remembering the first error
+pub struct FileScope<'a> {
+    pub script: &'a Script,
+    pub meta: Map,
+    pub error: RefCell<Option<String>>,
+}
+
+impl<'a> FileScope<'a> {
+    pub fn new(script: &'a Script, file: &path::Path) -> Self {
+        Self {
+            script,
+            meta: file_meta(file),
+            error: RefCell::new(None),
+        }
+    }
+
+    pub fn resolve(&self, code: &str, ctx: &SegmentContext) -> Option<String> {
+        let function = code.strip_prefix(FUNCTION_PREFIX)?;
+
+        match self.script.expand(function, ctx, &self.meta) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+}
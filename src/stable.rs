@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path;
+use std::sync::{Mutex, RwLock};
+use std::time;
+
+use crate::conf::lib::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Observation {
+    len: u64,
+    modified: time::SystemTime,
+}
+
+// Remembers what files looked like in previous loops, so that files still
+// being written (by a scanner, a browser...) aren't moved half-written
+#[derive(Debug, Default)]
+pub struct Tracker {
+    seen: Mutex<HashMap<path::PathBuf, (Observation, time::Instant)>>,
+    open: RwLock<HashSet<path::PathBuf>>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called once per loop, before handling any file
+    pub fn refresh(&self, config: &Config) {
+        self.seen.lock().unwrap().retain(|file, _| file.exists());
+
+        let mut open = self.open.write().unwrap();
+        if config.check_open {
+            *open = open_files();
+        } else {
+            open.clear();
+        }
+    }
+
+    // Records the current state of the file, without telling if it is stable
+    pub fn observe(&self, file: &path::Path) {
+        if let Some(observation) = observe(file) {
+            self.seen
+                .lock()
+                .unwrap()
+                .insert(file.to_owned(), (observation, time::Instant::now()));
+        }
+    }
+
+    pub fn is_stable(&self, file: &path::Path, config: &Config) -> bool {
+        let observation = match observe(file) {
+            Some(observation) => observation,
+            None => return false,
+        };
+
+        if config.min_age > 0 {
+            let age = observation.modified.elapsed().unwrap_or_default();
+            if age < time::Duration::from_millis(config.min_age as u64) {
+                log::debug!("File {:?} is too recent, deferring it", file);
+                return false;
+            }
+        }
+
+        if config.settle > 0 {
+            let settle = time::Duration::from_millis(config.settle as u64);
+            let mut seen = self.seen.lock().unwrap();
+
+            match seen.get(file) {
+                Some((previous, since)) if *previous == observation => {
+                    if since.elapsed() < settle {
+                        log::debug!("File {:?} hasn't settled yet, deferring it", file);
+                        return false;
+                    }
+                }
+                _ => {
+                    log::debug!("File {:?} changed since last loop, deferring it", file);
+                    seen.insert(file.to_owned(), (observation, time::Instant::now()));
+                    return false;
+                }
+            }
+        }
+
+        if config.check_open {
+            let open = self.open.read().unwrap();
+            if let Ok(canonical) = fs::canonicalize(file) {
+                if open.contains(&canonical) {
+                    log::debug!("File {:?} is opened by a process, deferring it", file);
+                    return false;
+                }
+            }
+        }
+
+        self.seen.lock().unwrap().remove(file);
+        true
+    }
+}
+
+fn observe(file: &path::Path) -> Option<Observation> {
+    let metadata = fs::metadata(file).ok()?;
+
+    Some(Observation {
+        len: metadata.len(),
+        modified: metadata.modified().ok()?,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn open_files() -> HashSet<path::PathBuf> {
+    let mut result = HashSet::new();
+
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(e) => {
+            log::warn!("Can't list processes to find opened files : {}", e);
+            return result;
+        }
+    };
+
+    for process in processes.flatten() {
+        if !process
+            .file_name()
+            .to_str()
+            .is_some_and(|pid| pid.chars().all(|c| c.is_ascii_digit()))
+        {
+            continue;
+        }
+
+        // Processes of other users aren't readable, and processes may end
+        // while we're reading them : those are just ignored
+        if let Ok(fds) = fs::read_dir(process.path().join("fd")) {
+            result.extend(fds.flatten().filter_map(|fd| fs::read_link(fd.path()).ok()));
+        }
+    }
+
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_files() -> HashSet<path::PathBuf> {
+    log::warn!("Checking for opened files is only supported on Linux");
+    HashSet::new()
+}
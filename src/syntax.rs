@@ -0,0 +1,458 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+use crate::sanitize::Sanitize;
+use crate::unicode::{Ascii, Normalization};
+
+// A token, written as one string or a list of alternatives
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "PatternSerDe", into = "PatternSerDe")]
+pub struct Pattern(Vec<String>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PatternSerDe {
+    One(String),
+    Alternatives(Vec<String>),
+}
+
+impl From<PatternSerDe> for Pattern {
+    fn from(pattern: PatternSerDe) -> Self {
+        match pattern {
+            PatternSerDe::One(one) => Self(vec![one]),
+            PatternSerDe::Alternatives(alternatives) => Self(alternatives),
+        }
+    }
+}
+
+impl From<Pattern> for PatternSerDe {
+    fn from(mut pattern: Pattern) -> Self {
+        if pattern.0.len() == 1 {
+            Self::One(pattern.0.remove(0))
+        } else {
+            Self::Alternatives(pattern.0)
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(one: &str) -> Self {
+        Self(vec![one.to_owned()])
+    }
+}
+
+impl From<Vec<String>> for Pattern {
+    fn from(alternatives: Vec<String>) -> Self {
+        Self(alternatives)
+    }
+}
+
+impl Pattern {
+    pub fn alternatives(&self) -> &[String] {
+        &self.0
+    }
+
+    // Used when writing the token, rather than reading it
+    pub fn first(&self) -> &str {
+        self.0.first().map_or("", String::as_str)
+    }
+
+    pub fn is_in(&self, input: &str) -> bool {
+        self.0
+            .iter()
+            .any(|alternative| !alternative.is_empty() && input.contains(alternative.as_str()))
+    }
+
+    // Length of the longest alternative `input` starts with
+    pub fn matches_start(&self, input: &str) -> Option<usize> {
+        self.0
+            .iter()
+            .filter(|alternative| {
+                !alternative.is_empty() && input.starts_with(alternative.as_str())
+            })
+            .map(String::len)
+            .max()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alternatives: Vec<_> = self
+            .0
+            .iter()
+            .map(|alternative| format!("`{}`", alternative))
+            .collect();
+        write!(f, "{}", alternatives.join(" or "))
+    }
+}
+
+// Tokens with a special meaning in file names
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Syntax {
+    // Separates each part of the file name
+    pub separator: Pattern,
+    pub begin_var: Pattern,
+    pub end_var: Pattern,
+    // A run of n of them stands for the n-th code used before
+    pub last_token: Pattern,
+    // Makes the next token, or character, literal
+    pub escape: char,
+    // How many separators belong to the file's own name. When None, it's
+    // found from the extension if `detect_extensions`, and is 1 otherwise
+    pub filename_separators: Option<usize>,
+    pub detect_extensions: bool,
+    // Extensions to recognize besides the known ones, like `tar.gz`
+    pub extensions: Vec<String>,
+    // How the directory and file names computed are made valid
+    pub sanitize: Sanitize,
+    // How accented letters are written, in file names and in what they expand to
+    pub unicode_normalization: Normalization,
+    // Whether the directory and file names computed are written with ASCII only
+    pub ascii: Ascii,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            separator: ".".into(),
+            begin_var: "{".into(),
+            end_var: "}".into(),
+            last_token: ",".into(),
+            escape: '\\',
+            filename_separators: None,
+            detect_extensions: false,
+            extensions: vec![],
+            sanitize: Sanitize::Posix,
+            unicode_normalization: Normalization::None,
+            ascii: Ascii::None,
+        }
+    }
+}
+
+impl Syntax {
+    // How a directory name, or a file name if `is_file`, computed from a file
+    // name is written in the destination
+    pub fn output(&self, name: &str, is_file: bool) -> String {
+        let name = self.unicode_normalization.apply(name);
+        let name = self.ascii.apply(&name, is_file);
+        self.sanitize.apply(&name, is_file)
+    }
+
+    // The token `input` starts with, and its length. The longest one wins
+    fn special(&self, input: &str) -> Option<(TokenKind, usize)> {
+        [
+            (TokenKind::Separator, &self.separator),
+            (TokenKind::BeginVar, &self.begin_var),
+            (TokenKind::EndVar, &self.end_var),
+            (TokenKind::Last(1), &self.last_token),
+        ]
+        .iter()
+        .filter_map(|(kind, pattern)| Some((*kind, pattern.matches_start(input)?)))
+        .max_by_key(|(_, length)| *length)
+    }
+
+    // How many separators of the tokenized file name are part of the file's
+    // own name : the ones of its extension, when it's detected
+    pub fn file_name_separators(&self, source: &str, tokens: &[Token]) -> usize {
+        match self.filename_separators {
+            Some(count) => return count,
+            None if !self.detect_extensions => return 1,
+            None => (),
+        }
+
+        // What's between separators, without escapes, and the separators
+        // as they're written
+        let mut pieces = vec![String::new()];
+        let mut separators = vec![];
+        for token in tokens {
+            match token.kind {
+                TokenKind::Separator => {
+                    pieces.push(String::new());
+                    separators.push(&source[token.span.start..token.span.end]);
+                }
+                _ => pieces.last_mut().unwrap().push_str(token.literal(source)),
+            }
+        }
+
+        extension_length(&pieces, &separators, &self.extensions)
+    }
+}
+
+// Extensions made of several parts, and single ones too long to be guessed
+pub const KNOWN_EXTENSIONS: &[&str] = &[
+    "tar.gz",
+    "tar.bz2",
+    "tar.xz",
+    "tar.zst",
+    "tar.lz",
+    "tar.lzma",
+    "tar.lz4",
+    "tar.br",
+    "tar.z",
+    "pkg.tar.zst",
+    "pkg.tar.xz",
+    "min.js",
+    "min.css",
+    "d.ts",
+    "user.js",
+    "blade.php",
+    "ipynb",
+    "torrent",
+    "sqlite",
+    "sqlite3",
+    "blend",
+    "xhtml",
+    "flatpak",
+    "appimage",
+    "crdownload",
+    "part",
+    "swp",
+];
+
+// Short alphanumeric endings, with at least one letter, are extensions
+fn looks_like_extension(piece: &str) -> bool {
+    (1..=4).contains(&piece.len())
+        && piece.chars().all(|c| c.is_ascii_alphanumeric())
+        && piece.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+// How many of the last pieces are the extension. There's always one piece
+// left for the name itself. Extensions are written with dots, so with
+// another separator only single ones can be found
+fn extension_length(pieces: &[String], separators: &[&str], extensions: &[String]) -> usize {
+    let pieces: Vec<String> = pieces.iter().map(|piece| piece.to_lowercase()).collect();
+
+    let listed = (1..pieces.len()).rev().find(|&length| {
+        let start = pieces.len() - length;
+        let mut extension = pieces[start].clone();
+        for (separator, piece) in separators[start..].iter().zip(&pieces[start + 1..]) {
+            extension.push_str(separator);
+            extension.push_str(piece);
+        }
+        KNOWN_EXTENSIONS.contains(&extension.as_str())
+            || extensions
+                .iter()
+                .any(|listed| listed.trim_start_matches('.').to_lowercase() == extension)
+    });
+
+    match listed {
+        Some(length) => length,
+        None if pieces.len() > 1 && looks_like_extension(pieces.last().unwrap()) => 1,
+        None => 0,
+    }
+}
+
+// Where something is in the file name, in bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Text,
+    // The escape character and the one it makes literal
+    Escaped,
+    Separator,
+    BeginVar,
+    EndVar,
+    // How many last tokens follow each other
+    Last(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    // What the token stands for, when it has no special meaning
+    pub fn literal<'a>(&self, source: &'a str) -> &'a str {
+        let text = &source[self.span.start..self.span.end];
+
+        match self.kind {
+            TokenKind::Escaped => {
+                let mut chars = text.chars();
+                let escape = chars.next().unwrap_or_default();
+                // An escape character ending the name is kept
+                match chars.as_str() {
+                    "" => &text[..escape.len_utf8()],
+                    escaped => escaped,
+                }
+            }
+            _ => text,
+        }
+    }
+}
+
+// Splits the file name in tokens, in one pass
+pub fn tokenize(source: &str, syntax: &Syntax) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut start = 0;
+
+    while let Some(c) = source[start..].chars().next() {
+        let rest = &source[start..];
+
+        let (kind, length) = if c == syntax.escape {
+            let escaped = &rest[c.len_utf8()..];
+            let length = match syntax.special(escaped) {
+                Some((_, length)) => length,
+                None => escaped.chars().next().map_or(0, char::len_utf8),
+            };
+            (TokenKind::Escaped, c.len_utf8() + length)
+        } else {
+            match syntax.special(rest) {
+                Some((TokenKind::Last(_), mut length)) => {
+                    let mut count = 1;
+                    while let Some((TokenKind::Last(_), next)) = syntax.special(&rest[length..]) {
+                        count += 1;
+                        length += next;
+                    }
+                    (TokenKind::Last(count), length)
+                }
+                Some(special) => special,
+                None => (TokenKind::Text, c.len_utf8()),
+            }
+        };
+
+        let span = Span {
+            start,
+            end: start + length,
+        };
+        start = span.end;
+
+        // Plain characters following each other are one token
+        match tokens.last_mut() {
+            Some(last) if kind == TokenKind::Text && last.kind == TokenKind::Text => {
+                last.span.end = span.end
+            }
+            _ => tokens.push(Token { kind, span }),
+        }
+    }
+
+    tokens
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Text(String, Span),
+    // How many last tokens, and how they were written
+    Last(usize, String, Span),
+    Variable(Vec<Node>, Span),
+}
+
+impl Node {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Text(_, span) | Self::Last(_, _, span) | Self::Variable(_, span) => *span,
+        }
+    }
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &str, span: Span) {
+    match nodes.last_mut() {
+        Some(Node::Text(last, last_span)) => {
+            last.push_str(text);
+            *last_span = last_span.to(span);
+        }
+        _ => nodes.push(Node::Text(text.to_owned(), span)),
+    }
+}
+
+fn current<'a>(stack: &'a mut [(Span, Vec<Node>)], root: &'a mut Vec<Node>) -> &'a mut Vec<Node> {
+    match stack.last_mut() {
+        Some((_, nodes)) => nodes,
+        None => root,
+    }
+}
+
+// Builds the tree of variables. Brackets which aren't closed, or closing
+// nothing, are plain text, and separators too
+pub fn parse(source: &str, tokens: &[Token]) -> Vec<Node> {
+    // Variables being parsed, with where they begin
+    let mut stack: Vec<(Span, Vec<Node>)> = vec![];
+    let mut root = vec![];
+
+    for token in tokens {
+        match token.kind {
+            TokenKind::BeginVar => stack.push((token.span, vec![])),
+            TokenKind::EndVar => match stack.pop() {
+                Some((begin, children)) => current(&mut stack, &mut root)
+                    .push(Node::Variable(children, begin.to(token.span))),
+                None => push_text(&mut root, token.literal(source), token.span),
+            },
+            TokenKind::Last(count) => current(&mut stack, &mut root).push(Node::Last(
+                count,
+                token.literal(source).to_owned(),
+                token.span,
+            )),
+            _ => push_text(
+                current(&mut stack, &mut root),
+                token.literal(source),
+                token.span,
+            ),
+        }
+    }
+
+    // What follows an unclosed bracket is part of the enclosing variable
+    while let Some((begin, children)) = stack.pop() {
+        let nodes = current(&mut stack, &mut root);
+
+        push_text(nodes, &source[begin.start..begin.end], begin);
+        for child in children {
+            match child {
+                Node::Text(text, span) => push_text(nodes, &text, span),
+                child => nodes.push(child),
+            }
+        }
+    }
+
+    root
+}
+
+// Something wrong with a part of a file name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Error {
+    // Shows the error under the file name, pointing at where it is
+    pub fn display_in(&self, source: &str) -> String {
+        let column = source[..self.span.start].chars().count();
+        let width = source[self.span.start..self.span.end].chars().count();
+
+        format!(
+            "{}\n\t{}\n\t{}{}",
+            self.message,
+            source,
+            " ".repeat(column),
+            "^".repeat(width.max(1))
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (at {}..{})",
+            self.message, self.span.start, self.span.end
+        )
+    }
+}
+
+impl std::error::Error for Error {}
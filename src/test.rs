@@ -1,8 +1,52 @@
+#[cfg(test)]
+use std::ops::Deref;
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+
+// A temporary directory, removed when dropped even if the test fails
+#[cfg(test)]
+struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fcs-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl Deref for TempDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[test]
 fn test_expand() {
     use std::collections::HashMap;
 
+    use crate::resolve::SegmentContext;
     use crate::run;
+    use crate::syntax::Syntax;
 
     let codes: HashMap<String, String> = [
         ("fr", "French"),
@@ -17,128 +61,1869 @@ fn test_expand() {
     .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
     .collect();
 
+    let syntax = Syntax::default();
+    let brackets = Syntax {
+        begin_var: "[".into(),
+        end_var: "]".into(),
+        ..Syntax::default()
+    };
+    let expand = |input: &str, syntax: &Syntax, last: &mut Vec<String>| {
+        run::expand(input, &codes, &SegmentContext::default(), syntax, last).unwrap()
+    };
+
+    assert_eq!(expand("{{fr}", &syntax, &mut vec![]), "{French");
+    assert_eq!(expand("{fr", &syntax, &mut vec![]), "{fr");
     assert_eq!(
-        run::expand(
-            &"{{fr}".to_string(),
-            &codes,
-            '{',
-            '}',
-            None,
-            &mut vec![],
-            ','
-        ),
-        "{French"
+        expand("{fr} {hst} (18th {cnt})", &syntax, &mut vec![]),
+        "French History (18th Century)"
     );
     assert_eq!(
-        run::expand(&"{fr".to_string(), &codes, '{', '}', None, &mut vec![], ','),
-        "{fr"
+        expand("{fr {hst} (18th {cnt})", &syntax, &mut vec![]),
+        "{fr History (18th Century)"
     );
+
     assert_eq!(
-        run::expand(
-            &"{fr} {hst} (18th {cnt})".to_string(),
-            &codes,
-            '{',
-            '}',
-            None,
-            &mut vec![],
-            ','
+        expand(
+            &expand("[sh{1}]", &syntax, &mut vec![]),
+            &brackets,
+            &mut vec![]
         ),
-        "French History (18th Century)"
+        "Shell One"
     );
+
+    assert_eq!(expand("{sh{2}}", &syntax, &mut vec![]), "Shell Two");
+
     assert_eq!(
+        expand("{,{2}}", &syntax, &mut vec!["sh".to_string()]),
+        "Shell Two"
+    );
+
+    // Nested variables are expanded before the variable containing them
+    let mut last = vec![];
+    assert_eq!(expand("{sh{1}}", &syntax, &mut last), "Shell One");
+    assert_eq!(last, vec!["1", "shone"]);
+}
+
+#[test]
+fn test_decode() {
+    use std::collections::HashMap;
+
+    use crate::resolve::SegmentContext;
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let history: Vec<_> = ["fr", "hst", "cnt", "1", "2", "shone", "shtwo"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+
+    assert_eq!(run::expand_last(2, &history), Some("hst".to_string()));
+    assert_eq!(run::expand_last(8, &history), Some("shtwofr".to_string()));
+    assert_eq!(run::expand_last(2, &[]), None);
+
+    let no_codes: HashMap<String, String> = HashMap::new();
+    let expand = |input: &str| {
         run::expand(
-            &"{fr {hst} (18th {cnt})".to_string(),
-            &codes,
-            '{',
-            '}',
-            None,
-            &mut vec![],
-            ','
-        ),
-        "{fr History (18th Century)"
+            input,
+            &no_codes,
+            &SegmentContext::default(),
+            &Syntax::default(),
+            &mut history.clone(),
+        )
+        .unwrap()
+    };
+
+    assert_eq!(expand("{,,}"), "hst");
+    assert_eq!(expand("{fr}"), "fr");
+    assert_eq!(expand("{,,,,,,,,}"), "shtwofr");
+    assert_eq!(expand("{,aa}"), "fraa");
+    assert_eq!(expand("{}"), "");
+    // Outside of variables, last tokens are plain text
+    assert_eq!(expand(",, {,}"), ",, fr");
+}
+
+#[test]
+fn test_stable() {
+    use std::{fs, thread, time};
+
+    use crate::conf::lib::Config;
+    use crate::stable::Tracker;
+
+    let root = TempDir::new("stable");
+    let file = root.join("file");
+    fs::write(&file, "being written").unwrap();
+
+    let config = Config {
+        settle: 50,
+        ..Config::default()
+    };
+    let tracker = Tracker::new();
+
+    assert!(!tracker.is_stable(&file, &config));
+    thread::sleep(time::Duration::from_millis(60));
+    assert!(tracker.is_stable(&file, &config));
+
+    fs::write(&file, "being written again").unwrap();
+    assert!(!tracker.is_stable(&file, &config));
+
+    let config = Config {
+        min_age: 60_000,
+        ..Config::default()
+    };
+    assert!(!tracker.is_stable(&file, &config));
+
+    fs::remove_file(&file).unwrap();
+    assert!(!tracker.is_stable(&file, &Config::default()));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_walk() {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use crate::conf::lib::SymlinkPolicy;
+    use crate::walk;
+
+    let root = TempDir::new("walk");
+    let outside = TempDir::new("walk-outside");
+    fs::create_dir_all(root.join("deep/deeper")).unwrap();
+
+    fs::write(root.join("top.txt"), "").unwrap();
+    fs::write(root.join(".hidden.txt"), "").unwrap();
+    fs::write(root.join("deep/middle.txt"), "").unwrap();
+    fs::write(root.join("deep/deeper/bottom.txt"), "").unwrap();
+    fs::write(outside.join("outside.txt"), "").unwrap();
+    symlink(root.join("top.txt"), root.join("link.txt")).unwrap();
+    symlink(&outside, root.join("linked")).unwrap();
+    symlink(&root, outside.join("loop")).unwrap();
+
+    let names = |max_depth, symlinks| -> HashSet<String> {
+        walk::files(&root, max_depth, symlinks)
+            .iter()
+            .map(|file| {
+                file.strip_prefix(&root)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    };
+    let set = |names: &[&str]| -> HashSet<String> { names.iter().map(|x| x.to_string()).collect() };
+
+    assert_eq!(names(Some(0), SymlinkPolicy::Skip), set(&["top.txt"]));
+    assert_eq!(
+        names(Some(1), SymlinkPolicy::Files),
+        set(&["top.txt", "link.txt", "deep/middle.txt"])
+    );
+    assert_eq!(
+        names(None, SymlinkPolicy::All),
+        set(&[
+            "top.txt",
+            "link.txt",
+            "deep/middle.txt",
+            "deep/deeper/bottom.txt",
+            "linked/outside.txt"
+        ])
+    );
+
+    // Symlinks to files outside of the watching directories aren't followed
+    let dirs = HashSet::from([root.clone()]);
+    let target = |link: &str| fs::canonicalize(root.join(link)).unwrap();
+    assert!(crate::run::watched(&target("link.txt"), &dirs));
+    assert!(!crate::run::watched(&target("linked/outside.txt"), &dirs));
+}
+
+#[test]
+fn test_prune() {
+    use std::fs;
+
+    use std::time::{Duration, SystemTime};
+
+    use crate::conf::lib::Config;
+    use crate::prune::{self, Pruner};
+
+    let root = TempDir::new("prune");
+    let file = root.join("very/deep/meaning/gr.statues.mp4");
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(&file, "").unwrap();
+    fs::create_dir_all(root.join("very/full")).unwrap();
+    fs::write(root.join("very/full/file"), "").unwrap();
+    fs::create_dir_all(root.join("untouched")).unwrap();
+
+    let mut config = Config {
+        prune_empty_dirs: true,
+        prune_grace: 60_000,
+        ..Config::default()
+    };
+    config.dirs.insert(root.clone());
+    let pruner = Pruner::new();
+
+    let changed = prune::changed(&file);
+    fs::remove_file(&file).unwrap();
+    pruner.moved_from(&file, changed);
+
+    pruner.prune(&config);
+    assert!(root.join("very/deep/meaning").exists());
+    assert!(!pruner.is_empty());
+
+    config.prune_grace = 0;
+    pruner.prune(&config);
+    assert!(!root.join("very/deep").exists());
+    assert!(root.join("very/full").exists());
+    assert!(root.join("untouched").exists());
+    assert!(pruner.is_empty());
+
+    // With --once, directories are removed if nothing else modified them
+    // for long enough, like one a scanner stopped filling
+    config.prune_grace = 60_000;
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for dir in ["old", "new"] {
+        let file = root.join(dir).join("file");
+        fs::create_dir_all(root.join(dir)).unwrap();
+        fs::write(&file, "").unwrap();
+        if dir == "old" {
+            fs::File::open(root.join(dir))
+                .unwrap()
+                .set_modified(hour_ago)
+                .unwrap();
+        }
+
+        let changed = prune::changed(&file);
+        fs::remove_file(&file).unwrap();
+        pruner.moved_from(&file, changed);
+    }
+    pruner.prune_once(&config);
+    assert!(!root.join("old").exists());
+    assert!(root.join("new").exists());
+    assert!(!pruner.is_empty());
+}
+
+#[test]
+fn test_move_env() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::syntax::Syntax;
+    use crate::{hooks, run};
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("asg", "Assignments")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    let new_name = run::get_new_name(
+        "asg.mt.integration.tex",
+        Path::new("/dest"),
+        &codes,
+        None,
+        &Syntax::default(),
+    )
+    .unwrap();
+
+    assert_eq!(new_name.codes, vec!["asg", "mt"]);
+    assert_eq!(new_name.segments, vec!["Assignments", "Mathematics"]);
+
+    let env: HashMap<_, _> =
+        hooks::move_env(Path::new("/src/asg.mt.integration.tex"), &new_name, ".")
+            .into_iter()
+            .collect();
+
+    assert_eq!(env["FCS_SOURCE"], "/src/asg.mt.integration.tex");
+    assert_eq!(
+        Path::new(&env["FCS_TARGET"]),
+        Path::new("/dest/Assignments/Mathematics/integration.tex")
+    );
+    assert_eq!(env["FCS_CODES"], "asg.mt");
+    assert_eq!(env["FCS_SEGMENT_COUNT"], "2");
+    assert_eq!(env["FCS_SEGMENT_2"], "Mathematics");
+}
+
+#[test]
+fn test_validate_target() {
+    use std::path::Path;
+
+    use crate::hooks::validate_target;
+
+    let dest = Path::new("/home/user/Documents");
+
+    assert_eq!(
+        validate_target(Path::new("Clients/ACME/report.pdf"), dest).unwrap(),
+        Path::new("/home/user/Documents/Clients/ACME/report.pdf")
+    );
+    assert_eq!(
+        validate_target(Path::new("/home/user/Documents/report.pdf"), dest).unwrap(),
+        Path::new("/home/user/Documents/report.pdf")
+    );
+    assert!(validate_target(Path::new("../report.pdf"), dest).is_err());
+    assert!(validate_target(Path::new("Clients/../../report.pdf"), dest).is_err());
+    assert!(validate_target(Path::new("/tmp/report.pdf"), dest).is_err());
+    assert!(validate_target(Path::new("/home/user/Documents"), dest).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hooks() {
+    use std::{fs, thread, time};
+
+    use crate::conf::lib::Hooks;
+    use crate::hooks::Runner;
+
+    let root = TempDir::new("hooks");
+    let log = root.join("log");
+    let env = [("LOG".to_string(), log.display().to_string())];
+
+    let runner = Runner::new();
+    let hooks = Hooks {
+        timeout: 200,
+        max_concurrent: 1,
+        ..Hooks::default()
+    };
+
+    assert!(runner.run("true", &env, &hooks));
+    assert!(!runner.run("exit 3", &env, &hooks));
+
+    // Killed before it could write anything
+    let start = time::Instant::now();
+    assert!(!runner.run("sleep 5; echo late >> \"$LOG\"", &env, &hooks));
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+    assert!(!log.exists());
+
+    // Hooks never overlap when only one may run at a time
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                runner.run(
+                    "echo start >> \"$LOG\"; sleep 0.05; echo end >> \"$LOG\"",
+                    &env,
+                    &hooks,
+                )
+            });
+        }
+    });
+    assert_eq!(fs::read_to_string(&log).unwrap(), "start\nend\n".repeat(4));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pre_move_hooks() {
+    use std::path::{Path, PathBuf};
+    use std::time;
+
+    use crate::conf::lib::Hooks;
+    use crate::hooks::{Decision, Runner};
+    use crate::run::NewName;
+
+    let new_name = NewName {
+        file: PathBuf::from("/dest/Mathematics/x.pdf"),
+        dir: PathBuf::from("/dest/Mathematics"),
+        codes: vec!["mt".to_string()],
+        segments: vec!["Mathematics".to_string()],
+    };
+    let hooks = Hooks {
+        timeout: 200,
+        ..Hooks::default()
+    };
+    let runner = Runner::new();
+    let filter = |command: &str| {
+        runner.filter(
+            command,
+            Path::new("/inbox/mt.x.pdf"),
+            &new_name,
+            &[],
+            &hooks,
+        )
+    };
+
+    assert_eq!(filter("echo accept").unwrap(), Decision::Accept);
+    assert_eq!(filter("echo skip").unwrap(), Decision::Skip);
+    assert_eq!(
+        filter("echo Other/x.pdf").unwrap(),
+        Decision::Rewrite(PathBuf::from("Other/x.pdf"))
+    );
+    // The hook gets the move as JSON
+    assert_eq!(
+        filter("grep -q '\"codes\":\\[\"mt\"\\]' && echo accept || echo skip").unwrap(),
+        Decision::Accept
+    );
+
+    assert!(filter("true").is_err());
+    assert!(filter("echo accept; exit 1").is_err());
+
+    // Neither a hook running for too long nor one leaving its output open
+    // keeps the file waiting
+    let start = time::Instant::now();
+    assert!(filter("sleep 5; echo accept").is_err());
+    assert!(filter("sleep 5 & echo accept").is_err());
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn test_resolver() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::resolve::{Chain, Resolver, SegmentContext};
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("cl", "Clients")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    // Client numbers only mean something under `cl`
+    let clients = |code: &str, ctx: &SegmentContext| -> Option<String> {
+        match (ctx.codes.last().map(String::as_str), code) {
+            (Some("cl"), "42") => Some("ACME".to_string()),
+            _ => None,
+        }
+    };
+
+    let chain = Chain::new().with(codes).with(clients);
+
+    assert_eq!(
+        chain.resolve("mt", &SegmentContext::default()),
+        Some("Mathematics".to_string())
+    );
+    assert_eq!(chain.resolve("42", &SegmentContext::default()), None);
+
+    let new_name = run::get_new_name(
+        "cl.42.{mt} for {42}.invoice.pdf",
+        Path::new("/dest"),
+        &chain,
+        None,
+        &Syntax::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        new_name.segments,
+        vec!["Clients", "ACME", "Mathematics for 42"]
     );
+}
+
+#[test]
+fn test_script() {
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::Path;
+
+    use crate::conf::lib::Config;
+    use crate::dedupe::Index;
+    use crate::hooks::Runner;
+    use crate::resolve::{Resolver, SegmentContext};
+    use crate::run;
+    use crate::script::{FileScope, Script, FUNCTION_PREFIX};
+    use crate::syntax::Syntax;
+
+    let script = Script::compile(
+        r#"
+        fn schoolyear(meta) {
+            if meta.month >= 9 { `${meta.year}-${meta.year + 1}` } else { `${meta.year - 1}-${meta.year}` }
+        }
+        fn client(meta) { if meta.codes[meta.index - 1] == "cl" { "ACME" } else { "Unknown" } }
+        fn forever() { loop {} }
+        fn classify(name, meta) { if name.ends_with(".tmp") { "Trash/" + name } }
+        "#,
+        50,
+    )
+    .unwrap();
 
+    let file = std::env::temp_dir().join(format!("fcs-test-script-{}", std::process::id()));
+    fs::write(&file, "").unwrap();
+    let scope = FileScope::new(&script, &file);
+    fs::remove_file(&file).unwrap();
+
+    let codes: HashMap<String, String> = [("cl", "Clients")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+    let resolver = |code: &str, ctx: &SegmentContext| {
+        if code.starts_with(FUNCTION_PREFIX) {
+            scope.resolve(code, ctx)
+        } else {
+            codes.resolve(code, ctx)
+        }
+    };
+
+    let new_name = run::get_new_name(
+        "cl.{fn:client}.invoice.pdf",
+        Path::new("/dest"),
+        &resolver,
+        None,
+        &Syntax::default(),
+    )
+    .unwrap();
+    assert_eq!(new_name.segments, vec!["Clients", "ACME"]);
+    assert!(scope.error.take().is_none());
+
+    let year = scope.resolve("fn:schoolyear", &SegmentContext::default());
+    assert!(year.is_some_and(|year| year.len() == 9));
+
+    // Errors are kept to be reported with the file
+    assert_eq!(
+        scope.resolve("fn:forever", &SegmentContext::default()),
+        None
+    );
+    assert!(scope.error.take().unwrap().contains("forever"));
     assert_eq!(
+        scope.resolve("fn:missing", &SegmentContext::default()),
+        None
+    );
+    assert!(scope.error.take().is_some());
+
+    assert_eq!(
+        script.classify("a.tmp", &scope.meta),
+        Ok(Some("Trash/a.tmp".to_string()))
+    );
+    assert_eq!(script.classify("a.pdf", &scope.meta), Ok(None));
+
+    // Files without codes are classified too
+    let root = TempDir::new("script");
+    fs::create_dir_all(root.join("inbox")).unwrap();
+    fs::create_dir_all(root.join("dest")).unwrap();
+    fs::write(root.join("inbox/a.tmp"), "").unwrap();
+    let config = Config {
+        dest: root.join("dest"),
+        dirs: HashSet::from([root.join("inbox")]),
+        ..Config::default()
+    };
+    assert!(!run::wanted("a.tmp", &config, None));
+    assert!(run::wanted("a.tmp", &config, Some(&script)));
+    assert!(run::handle(
+        &root.join("inbox/a.tmp"),
+        &config,
+        &Runner::new(),
+        Some(&script),
+        &Index::new(),
+        None
+    ));
+    assert!(root.join("dest/Trash/a.tmp").is_file());
+
+    assert!(Script::compile("fn broken( {", 50).is_err());
+}
+
+#[test]
+fn test_transform() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::resolve::SegmentContext;
+    use crate::run;
+    use crate::syntax::Syntax;
+    use crate::transform::{self, Transform};
+
+    let codes: HashMap<String, String> = [("fr", "French"), ("hst", "modern history"), ("n", "7")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    let expand = |input: &str| {
         run::expand(
-            &run::expand(
-                &"[sh{1}]".to_string(),
-                &codes,
-                '{',
-                '}',
-                None,
-                &mut vec![],
-                ','
-            ),
+            input,
             &codes,
-            '[',
-            ']',
-            None,
+            &SegmentContext::default(),
+            &Syntax::default(),
             &mut vec![],
-            ','
-        ),
-        "Shell One"
+        )
+        .unwrap()
+    };
+
+    assert_eq!(expand("{upper:fr}"), "FRENCH");
+    assert_eq!(expand("{upper:{fr}}"), "FRENCH");
+    assert_eq!(expand("{lower:fr} {title:hst}"), "french Modern History");
+    assert_eq!(expand("{truncate:2:fr}"), "Fr");
+    assert_eq!(expand("{pad:3:n}"), "007");
+    assert_eq!(expand("{pad:3:12345}"), "12345");
+    assert_eq!(expand("{replace: :_:hst}"), "modern_history");
+    assert_eq!(expand("{upper:truncate:3:fr}"), "FRE");
+    assert_eq!(expand("{trim:  fr }"), "fr");
+
+    // Not function calls, so used plain
+    assert_eq!(expand("{pad:x:n}"), "pad:x:n");
+    assert_eq!(expand("{unknown:fr}"), "unknown:fr");
+
+    // Outside of variables, parts are codes or plain text
+    let segments = |name: &str| {
+        run::get_new_name(name, Path::new("/dest"), &codes, None, &Syntax::default())
+            .unwrap()
+            .segments
+    };
+    assert_eq!(segments("upper:fr.a.pdf"), vec!["upper:fr"]);
+    assert_eq!(segments("{upper:fr}.a.pdf"), vec!["FRENCH"]);
+
+    assert_eq!(
+        transform::parse("replace:a:b:c:d"),
+        Some((Transform::Replace("a".to_string(), "b".to_string()), "c:d"))
+    );
+    assert_eq!(transform::parse("upper"), None);
+}
+
+#[test]
+fn test_fallback() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("a|b", "Pipe")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    let new_name = |name: &str| {
+        run::get_new_name(name, Path::new("/dest"), &codes, None, &Syntax::default())
+            .map(|new_name| new_name.segments)
+    };
+
+    assert_eq!(new_name("{mt|Misc}.a.pdf").unwrap(), vec!["Mathematics"]);
+    assert_eq!(new_name("{mth|Misc}.a.pdf").unwrap(), vec!["Misc"]);
+    assert_eq!(
+        new_name("{mth|Misc}.mt.a.pdf").unwrap(),
+        vec!["Misc", "Mathematics"]
+    );
+    assert_eq!(
+        new_name("{upper:mt|Misc}.a.pdf").unwrap(),
+        vec!["MATHEMATICS"]
+    );
+    assert_eq!(new_name("{upper:mth|Misc}.a.pdf").unwrap(), vec!["Misc"]);
+    assert_eq!(new_name("{a|b}.a.pdf").unwrap(), vec!["Pipe"]);
+    assert_eq!(
+        new_name("Notes {mt|?}.a.pdf").unwrap(),
+        vec!["Notes Mathematics"]
+    );
+
+    assert!(new_name("{mth|?}.a.pdf").is_err());
+    assert!(new_name("mt.{mth|?}.a.pdf").is_err());
+
+    // Outside of variables, `|` is plain text
+    assert_eq!(new_name("a|b.a.pdf").unwrap(), vec!["Pipe"]);
+    assert_eq!(new_name("x|y.a.pdf").unwrap(), vec!["x|y"]);
+    assert_eq!(
+        new_name("mt.mth|?.a.pdf").unwrap(),
+        vec!["Mathematics", "mth|?"]
+    );
+}
+
+#[test]
+fn test_escape() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::resolve::SegmentContext;
+    use crate::run;
+    use crate::syntax::{self, Syntax, TokenKind};
+
+    let kinds = |source: &str| -> Vec<TokenKind> {
+        syntax::tokenize(source, &Syntax::default())
+            .iter()
+            .map(|token| token.kind)
+            .collect()
+    };
+    let literal = |source: &str| -> String {
+        syntax::tokenize(source, &Syntax::default())
+            .iter()
+            .map(|token| token.literal(source))
+            .collect()
+    };
+
+    assert_eq!(
+        kinds(r"a\.b.c"),
+        vec![
+            TokenKind::Text,
+            TokenKind::Escaped,
+            TokenKind::Text,
+            TokenKind::Separator,
+            TokenKind::Text
+        ]
     );
+    assert_eq!(
+        kinds(r"a\.b.c\\.d")
+            .iter()
+            .filter(|&&kind| kind == TokenKind::Separator)
+            .count(),
+        2
+    );
+    assert_eq!(literal(r"v1\.2 \{draft\} \\ \"), r"v1.2 {draft} \ \");
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("a}b", "Brace"), ("br", "{mt}")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    let new_name = |name: &str| {
+        run::get_new_name(name, Path::new("/dest"), &codes, None, &Syntax::default()).unwrap()
+    };
 
+    let result = new_name(r"mt.v1\.2 \{draft\} {mt}.notes.txt");
+    assert_eq!(
+        result.segments,
+        vec!["Mathematics", "v1.2 {draft} Mathematics"]
+    );
+    assert_eq!(
+        result.file,
+        Path::new("/dest/Mathematics/v1.2 {draft} Mathematics/notes.txt")
+    );
+
+    assert_eq!(new_name(r"{a\}b}\,.x.txt").segments, vec!["Brace,"]);
+
+    // Meanings are plain text
     assert_eq!(
         run::expand(
-            &run::expand(
-                &"{sh{2}}".to_string(),
-                &codes,
-                '{',
-                '}',
-                Some(3),
-                &mut vec![],
-                ','
-            ),
+            "{br}",
             &codes,
-            '{',
-            '}',
-            None,
-            &mut vec![],
-            ','
+            &SegmentContext::default(),
+            &Syntax::default(),
+            &mut vec![]
         ),
-        "Shell Two"
+        Ok("{mt}".to_string())
+    );
+    assert_eq!(
+        new_name(r"mt.a.b\.txt").file,
+        Path::new("/dest/Mathematics/a.b.txt")
     );
+}
+
+#[test]
+fn test_syntax() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::run;
+    use crate::syntax::{self, Node, Span, Syntax};
 
+    let source = "{a{b}} {c";
+    let nodes = syntax::parse(source, &syntax::tokenize(source, &Syntax::default()));
     assert_eq!(
-        run::expand(
-            &run::expand(
-                &"{,{2}}",
-                &codes,
-                '{',
-                '}',
-                None,
-                &mut vec!["sh".to_string()],
-                ','
+        nodes,
+        vec![
+            Node::Variable(
+                vec![
+                    Node::Text("a".to_string(), Span { start: 1, end: 2 }),
+                    Node::Variable(
+                        vec![Node::Text("b".to_string(), Span { start: 3, end: 4 })],
+                        Span { start: 2, end: 5 }
+                    )
+                ],
+                Span { start: 0, end: 6 }
             ),
-            &codes,
-            '{',
-            '}',
-            None,
-            &mut vec!["sh".to_string()],
-            ','
-        ),
-        "Shell Two"
+            Node::Text(" {c".to_string(), Span { start: 6, end: 9 })
+        ]
     );
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    // Tokens longer than a byte
+    let syntax = Syntax {
+        separator: "·".into(),
+        begin_var: "«".into(),
+        end_var: "»".into(),
+        last_token: "…".into(),
+        escape: '¦',
+        ..Syntax::default()
+    };
+    let new_name = run::get_new_name(
+        "mt·«…» ¦·é·x·notes.txt",
+        Path::new("/dest"),
+        &codes,
+        None,
+        &syntax,
+    )
+    .unwrap();
+    assert_eq!(new_name.segments, vec!["Mathematics", "Mathematics ·é"]);
+    assert_eq!(
+        new_name.file,
+        Path::new("/dest/Mathematics/Mathematics ·é/x·notes.txt")
+    );
+
+    let error = run::get_new_name(
+        "mt.{mth|?}.a.pdf",
+        Path::new("/dest"),
+        &codes,
+        None,
+        &Syntax::default(),
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.ends_with("\tmt.{mth|?}.a.pdf\n\t   ^^^^^^^"));
 }
 
 #[test]
-fn test_decode() {
+fn test_pattern() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
     use crate::run;
+    use crate::syntax::{Pattern, Syntax};
 
-    let history: Vec<_> = vec!["fr", "hst", "cnt", "1", "2", "shone", "shtwo"]
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("asg", "Assignments")]
         .iter()
-        .map(|x| x.to_string())
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
         .collect();
 
-    assert_eq!(run::expand_last(&",,".to_string(), &history, ','), "hst");
+    let syntax = Syntax {
+        separator: vec![" - ".to_string(), "__".to_string(), ".".to_string()].into(),
+        begin_var: "<<".into(),
+        end_var: ">>".into(),
+        last_token: "_".into(),
+        ..Syntax::default()
+    };
 
-    assert_eq!(run::expand_last(&"fr".to_string(), &history, ','), "fr");
+    let new_name = run::get_new_name(
+        "mt - asg__<<_>> notes.final.txt",
+        Path::new("/dest"),
+        &codes,
+        None,
+        &syntax,
+    )
+    .unwrap();
+    assert_eq!(new_name.codes, vec!["mt", "asg", "<<_>> notes"]);
+    assert_eq!(
+        new_name.segments,
+        vec!["Mathematics", "Assignments", "Mathematics notes"]
+    );
+    assert_eq!(
+        new_name.file,
+        Path::new("/dest/Mathematics/Assignments/Mathematics notes/final.txt")
+    );
+
+    // One string, or several of them
+    let pattern: Pattern = serde_yaml::from_str("\" - \"").unwrap();
+    assert_eq!(pattern, Pattern::from(" - "));
+    let pattern: Pattern = serde_yaml::from_str("[_, .]").unwrap();
+    assert_eq!(pattern.alternatives(), ["_", "."]);
+    assert_eq!(
+        serde_yaml::from_str::<Pattern>(&serde_yaml::to_string(&pattern).unwrap()).unwrap(),
+        pattern
+    );
+    assert_eq!(pattern.to_string(), "`_` or `.`");
+}
+
+#[test]
+fn test_extensions() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::run;
+    use crate::syntax::{self, Syntax};
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("asg", "Assignments")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+
+    let new_name = |name: &str, syntax: &Syntax| {
+        run::get_new_name(name, Path::new("/dest"), &codes, None, syntax).unwrap()
+    };
+
+    // Unless extensions are detected, the file's own name has one separator
+    let result = new_name("mt.asg.todo", &Syntax::default());
+    assert_eq!(result.segments, vec!["Mathematics"]);
+    assert_eq!(result.file, Path::new("/dest/Mathematics/asg.todo"));
+
+    let syntax = Syntax {
+        detect_extensions: true,
+        ..Syntax::default()
+    };
+
+    let result = new_name("mt.asg.notes.tar.gz", &syntax);
+    assert_eq!(result.segments, vec!["Mathematics", "Assignments"]);
+    assert_eq!(
+        result.file,
+        Path::new("/dest/Mathematics/Assignments/notes.tar.gz")
+    );
+    assert_eq!(
+        new_name(r"mt.report\.v2\.final.PDF", &syntax).file,
+        Path::new("/dest/Mathematics/report.v2.final.PDF")
+    );
+
+    // Without an extension, the last part is the whole file name
+    let result = new_name("mt.asg.notes", &syntax);
+    assert_eq!(result.segments, vec!["Mathematics", "Assignments"]);
+    assert_eq!(
+        result.file,
+        Path::new("/dest/Mathematics/Assignments/notes")
+    );
+
+    let listed = Syntax {
+        extensions: vec![".log.1".to_string()],
+        ..syntax.clone()
+    };
+    assert_eq!(
+        new_name("mt.server.log.1", &syntax).segments,
+        vec!["Mathematics", "server", "log"]
+    );
+    assert_eq!(
+        new_name("mt.server.log.1", &listed).file,
+        Path::new("/dest/Mathematics/server.log.1")
+    );
+
+    // The count of separators in the file name overrides extensions
+    let counted = Syntax {
+        filename_separators: Some(2),
+        ..syntax.clone()
+    };
+    assert_eq!(
+        new_name("mt.a.b.c", &counted).file,
+        Path::new("/dest/Mathematics/a.b.c")
+    );
+
+    // Extensions are written with dots, whatever the separator
+    let underscored = Syntax {
+        separator: "_".into(),
+        ..syntax.clone()
+    };
+    assert_eq!(
+        new_name("mt_asg_notes_tar_gz", &underscored).file,
+        Path::new("/dest/Mathematics/Assignments/notes/tar_gz")
+    );
+    assert_eq!(
+        new_name("mt_asg_notes.tar.gz", &underscored).file,
+        Path::new("/dest/Mathematics/Assignments/notes.tar.gz")
+    );
+
+    // The extension never takes the whole name
+    let name = "tar.gz";
+    assert_eq!(
+        syntax.file_name_separators(name, &syntax::tokenize(name, &syntax)),
+        1
+    );
+}
+
+#[test]
+fn test_case() {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::case;
+    use crate::conf::lib::DestCase;
+    use crate::resolve::{CaseInsensitive, Code, Resolver};
+    use crate::run;
+    use crate::syntax::Syntax;
+    use crate::unicode::Normalization;
+
+    let root = TempDir::new("case");
+    fs::create_dir_all(root.join("mathematics/Assignments")).unwrap();
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("asg", "assignments")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+    let new_name = |name: &str, policy: DestCase| {
+        let new_name = run::get_new_name(name, &root, &codes, None, &Syntax::default()).unwrap();
+        case::normalize(new_name, &root, policy, Normalization::None)
+    };
+
+    // Existing directories differing in case are used, unless it's preserved
+    let result = new_name("mt.asg.notes.txt", DestCase::Preserve);
+    assert_eq!(result.segments, vec!["Mathematics", "assignments"]);
+    assert_eq!(result.file, root.join("Mathematics/assignments/notes.txt"));
+    let result = new_name("mt.asg.notes.txt", DestCase::Title);
+    assert_eq!(result.segments, vec!["mathematics", "Assignments"]);
+    assert_eq!(
+        new_name("mt.asg.notes.txt", DestCase::Lower).file,
+        root.join("mathematics/Assignments/notes.txt")
+    );
+
+    let result = new_name("mt.new stuff.notes.txt", DestCase::Title);
+    assert_eq!(result.segments, vec!["mathematics", "New Stuff"]);
+    assert_eq!(result.dir, root.join("mathematics/New Stuff"));
+
+    let result = new_name("asg.mt.Notes.TXT", DestCase::Lower);
+    assert_eq!(result.dir, root.join("assignments/mathematics"));
+    assert_eq!(result.file, root.join("assignments/mathematics/Notes.TXT"));
+    assert_eq!(case::apply(DestCase::Preserve, "eN fR"), "eN fR");
+
+    // With case_insensitive_codes, codes are lowercased when read, then
+    // looked up lowercased, namespaces included
+    let namespaced: HashMap<String, Code> =
+        serde_yaml::from_str("mt: {name: Mathematics, codes: {alg: Algebra}}").unwrap();
+    let segments = |name: &str, codes: &dyn Resolver| {
+        run::get_new_name(name, &root, codes, None, &Syntax::default())
+            .unwrap()
+            .segments
+    };
+    assert_eq!(
+        segments("MT.Alg.x.pdf", &CaseInsensitive(&namespaced)),
+        vec!["Mathematics", "Algebra"]
+    );
+    assert_eq!(segments("MT.Alg.x.pdf", &namespaced), vec!["MT", "Alg"]);
+}
+
+#[test]
+fn test_namespace() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::resolve::Code;
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let codes: HashMap<String, Code> = serde_yaml::from_str(
+        "
+        mt:
+          name: Mathematics
+          codes:
+            alg: Algebra
+            geo:
+              name: Geometry
+              codes:
+                tri: Triangles
+        cs: {name: Computer Science, codes: {alg: Algorithms}}
+        alg: Algae
+        tri: Tribes
+        asg: Assignments
+        ",
+    )
+    .unwrap();
+    assert_eq!(codes["cs"].meaning(), "Computer Science");
+
+    let segments = |name: &str| {
+        run::get_new_name(name, Path::new("/dest"), &codes, None, &Syntax::default())
+            .unwrap()
+            .segments
+    };
+
+    assert_eq!(segments("mt.alg.x.pdf"), vec!["Mathematics", "Algebra"]);
+    assert_eq!(
+        segments("cs.alg.x.pdf"),
+        vec!["Computer Science", "Algorithms"]
+    );
+    assert_eq!(segments("alg.x.pdf"), vec!["Algae"]);
+    // Only the previous part's namespace is used, then the global codes
+    assert_eq!(
+        segments("mt.geo.tri.x.pdf"),
+        vec!["Mathematics", "Geometry", "Triangles"]
+    );
+    assert_eq!(
+        segments("mt.asg.alg.tri.x.pdf"),
+        vec!["Mathematics", "Assignments", "Algae", "Tribes"]
+    );
+    assert_eq!(segments("mt.{alg}.x.pdf"), vec!["Mathematics", "Algebra"]);
+
+    // The namespace is the one of the code a part was looked up with
+    assert_eq!(segments("{mt}.alg.x.pdf"), vec!["Mathematics", "Algebra"]);
+    assert_eq!(
+        segments("{mt|Misc}.alg.x.pdf"),
+        vec!["Mathematics", "Algebra"]
+    );
+    assert_eq!(
+        segments("{upper:cs}.alg.x.pdf"),
+        vec!["COMPUTER SCIENCE", "Algorithms"]
+    );
+    assert_eq!(
+        segments("cs.{,-1}.alg.x.pdf"),
+        vec!["Computer Science", "Computer Science", "Algorithms"]
+    );
+    assert_eq!(segments("{mth|Misc}.alg.x.pdf"), vec!["Misc", "Algae"]);
+    assert_eq!(
+        segments("Notes {mt}.alg.x.pdf"),
+        vec!["Notes Mathematics", "Algae"]
+    );
+}
+
+#[test]
+fn test_references() {
+    use std::collections::{HashMap, HashSet};
 
+    use crate::conf::lib::Config;
+    use crate::conf::references;
+    use crate::resolve::Code;
+    use crate::syntax::Syntax;
+
+    let codes: HashMap<String, Code> = serde_yaml::from_str(
+        r"
+        fr: French
+        hst: History
+        frh: '{fr} {hst}'
+        up: '{upper:frh}'
+        misc: '{xyz|Misc}'
+        broken: '{fr} {xyz}'
+        escaped: '\{fr\} a\.b {fr}'
+        a: '{b}'
+        b: '{a}'
+        c: '{a}'
+        mt: {name: '{up}', codes: {x: '{mt}'}}
+        ",
+    )
+    .unwrap();
+
+    let expanded = references::expand(&codes, &Syntax::default(), false);
+    let meaning = |code: &str| expanded.get(code).map(Code::meaning);
+    assert_eq!(meaning("frh"), Some("French History"));
+    assert_eq!(meaning("up"), Some("FRENCH HISTORY"));
+    assert_eq!(meaning("misc"), Some("Misc"));
+    assert_eq!(meaning("mt"), Some("FRENCH HISTORY"));
     assert_eq!(
-        run::expand_last(&",,,,,,,,".to_string(), &history, ','),
-        "shtwofr"
+        expanded["mt"].codes().unwrap()["x"].meaning(),
+        "FRENCH HISTORY"
     );
-    assert_eq!(run::expand_last(&",aa".to_string(), &history, ','), "fraa");
-    assert_eq!(run::expand_last(&"".to_string(), &history, ','), "");
+    // What isn't a reference is kept as it's written
+    assert_eq!(meaning("broken"), Some("French {xyz}"));
+    assert_eq!(meaning("escaped"), Some("\\{fr\\} a\\.b French"));
+    for code in ["a", "b", "c"] {
+        assert_eq!(meaning(code), None);
+    }
+
+    // Aliases mean the same as their code
+    let root = TempDir::new("references");
+    let mut config = Config {
+        dest: root.clone(),
+        dirs: HashSet::from([root.clone()]),
+        codes: serde_yaml::from_str(
+            "
+            mt: {name: Mathematics, aliases: [math, maths, fr]}
+            fr: French
+            ",
+        )
+        .unwrap(),
+        ..Config::default()
+    };
+    assert!(!config.clean(true));
+    assert_eq!(config.codes["maths"].meaning(), "Mathematics");
+    assert_eq!(config.codes["fr"].meaning(), "French");
+    assert_eq!(config.codes["mt"].aliases(), ["math", "maths"]);
+}
+
+#[test]
+fn test_local() {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::conf::lib::Config;
+    use crate::dedupe::Index;
+    use crate::hooks::Runner;
+    use crate::local::{self, Local};
+    use crate::run;
+
+    let root = TempDir::new("local");
+    let inbox = root.join("inbox");
+    let dest = root.join("dest");
+    fs::create_dir_all(inbox.join("school/deep")).unwrap();
+    fs::create_dir_all(inbox.join("work")).unwrap();
+    fs::create_dir_all(&dest).unwrap();
+
+    fs::write(
+        inbox.join("school").join(local::FILE_NAME),
+        "codes: {alg: Algebra}\ndest: School\nsleep: 10\n",
+    )
+    .unwrap();
+    fs::write(
+        inbox.join("school/deep").join(local::FILE_NAME),
+        "codes: {mt: Maths}\nseparator: _\n",
+    )
+    .unwrap();
+    // Destinations can't leave the one above
+    fs::write(
+        inbox.join("work").join(local::FILE_NAME),
+        format!("codes: {{alg: Algae}}\ndest: {}\n", root.display()),
+    )
+    .unwrap();
+
+    let files = vec![
+        inbox.join("school/deep/mt_alg_x.pdf"),
+        inbox.join("school/alg.x.pdf"),
+        inbox.join("work/mt.x.pdf"),
+    ];
+    for file in &files {
+        fs::write(file, "").unwrap();
+    }
+
+    let mut global = Config {
+        dest: dest.clone(),
+        dirs: HashSet::from([inbox.clone()]),
+        codes: serde_yaml::from_str("mt: Mathematics").unwrap(),
+        ..Config::default()
+    };
+    assert!(!global.clean(true));
+
+    let found = local::find(&inbox, &files);
+    assert_eq!(found.len(), 3);
+    let mut local = Local::new();
+    local.update(&found, &global);
+
+    let deep = local.config(&files[0], &global);
+    assert_eq!(deep.dest, dest.join("School"));
+    assert_eq!(deep.codes["mt"].meaning(), "Maths");
+    assert_eq!(deep.codes["alg"].meaning(), "Algebra");
+    assert_eq!(deep.separator.first(), "_");
+
+    let school = local.config(&files[1], &global);
+    assert_eq!(school.codes["mt"].meaning(), "Mathematics");
+    assert_eq!(school.separator.first(), ".");
+    assert_eq!(school.sleep, global.sleep);
+    // Reading configuration files doesn't create anything
+    assert!(!dest.join("School").exists());
+
+    let work = local.config(&files[2], &global);
+    assert!(!work.codes.contains_key("alg"));
+    assert_eq!(work.dest, dest);
+
+    // The destination is created when a file is moved there
+    let runner = Runner::new();
+    let index = Index::new();
+    assert!(run::handle(&files[1], school, &runner, None, &index, None));
+    assert!(dest.join("School/Algebra/x.pdf").is_file());
+
+    // Files are read again once they changed
+    fs::write(
+        inbox.join("school").join(local::FILE_NAME),
+        "codes: {alg: Algorithms}\n",
+    )
+    .unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::options()
+        .write(true)
+        .open(inbox.join("school").join(local::FILE_NAME))
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+    local.update(&found, &global);
+    assert_eq!(
+        local.config(&files[0], &global).codes["alg"].meaning(),
+        "Algorithms"
+    );
+    assert_eq!(local.config(&files[1], &global).dest, dest);
+}
+
+#[test]
+fn test_back_reference() {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let codes: HashMap<String, String> = [("mt", "Mathematics"), ("asg", "Assignments")]
+        .iter()
+        .map(|tuple| (String::from(tuple.0), String::from(tuple.1)))
+        .collect();
+    let new_name =
+        |name: &str| run::get_new_name(name, Path::new("/dest"), &codes, None, &Syntax::default());
+
+    assert_eq!(
+        new_name("mt.asg.{,1} {,-1} {raw:,-1} {upper:{,-2}}.x.pdf")
+            .unwrap()
+            .segments,
+        vec![
+            "Mathematics",
+            "Assignments",
+            "Mathematics Assignments asg MATHEMATICS"
+        ]
+    );
+    // The repeated last token still refers to codes
+    assert_eq!(
+        new_name("mt.asg.{,,}.x.pdf").unwrap().segments,
+        vec!["Mathematics", "Assignments", "Assignments"]
+    );
+
+    for name in ["mt.{,2}.x.pdf", "mt.{,-2}.x.pdf", "mt.{,0}.x.pdf"] {
+        assert!(new_name(name)
+            .unwrap_err()
+            .to_string()
+            .contains("There's no part"));
+    }
+}
+
+#[test]
+fn test_meanings() {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::case;
+    use crate::conf::lib::{Config, DestCase};
+    use crate::run;
+    use crate::syntax::Syntax;
+    use crate::unicode::Normalization;
+
+    let root = TempDir::new("meanings");
+    let mut config = Config {
+        dest: root.clone(),
+        dirs: HashSet::from([root.clone()]),
+        codes: serde_yaml::from_str(
+            r#"
+            sci: Science/Physics
+            tmp: ""
+            mt: Mathematics
+            up: ../x
+            abs: /etc
+            dot: "."
+            empty: a//b
+            trailing: a/
+            sneaky: ".{tmp}"
+            "#,
+        )
+        .unwrap(),
+        ..Config::default()
+    };
+    assert!(!config.clean(true));
+
+    let mut valid: Vec<&String> = config.codes.keys().collect();
+    valid.sort();
+    assert_eq!(valid, ["mt", "sci", "tmp"]);
+
+    let new_name = run::get_new_name(
+        "sci.tmp.mt.x.pdf",
+        &root,
+        &config.codes,
+        None,
+        &Syntax::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        new_name.segments,
+        vec!["Science/Physics", "", "Mathematics"]
+    );
+    assert_eq!(
+        new_name.file,
+        root.join("Science/Physics/Mathematics/x.pdf")
+    );
+
+    fs::create_dir_all(root.join("science")).unwrap();
+    let new_name = case::normalize(new_name, &root, DestCase::Lower, Normalization::None);
+    assert_eq!(
+        new_name.segments,
+        vec!["science/physics", "", "mathematics"]
+    );
+    assert_eq!(
+        new_name.file,
+        root.join("science/physics/mathematics/x.pdf")
+    );
+}
+
+#[test]
+fn test_confine() {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::confine;
+    use crate::resolve::SegmentContext;
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let codes = |code: &str, _: &SegmentContext| match code {
+        "mt" => Some("Mathematics".to_string()),
+        "nul" => Some("a\0b".to_string()),
+        _ => None,
+    };
+    let syntax = Syntax {
+        separator: "_".into(),
+        detect_extensions: true,
+        ..Syntax::default()
+    };
+    let new_name = |name: &str| run::get_new_name(name, Path::new("/dest"), &codes, None, &syntax);
+
+    assert!(new_name("mt_x.pdf").is_ok());
+    for name in [
+        "mt_.._x.pdf",
+        "mt_._x.pdf",
+        "mt_/etc_x.pdf",
+        "mt_{x|/etc}_x.pdf",
+        "mt_{x|a/../..}_x.pdf",
+        "mt_nul_x.pdf",
+        "mt_..",
+    ] {
+        assert!(new_name(name).is_err(), "{}", name);
+    }
+    assert!(confine::check_component("a\0b")
+        .unwrap_err()
+        .contains("NUL"));
+
+    let root = TempDir::new("confine");
+    let dest = root.join("dest");
+    let outside = root.join("outside");
+    fs::create_dir_all(dest.join("inside")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+
+    assert!(confine::check_inside(&dest.join("inside/new/x.pdf"), &dest).is_ok());
+    assert!(confine::check_inside(&dest.join("x.pdf"), &dest).is_ok());
+    assert!(confine::check_inside(&dest.join("../outside/x.pdf"), &dest).is_err());
+    assert!(confine::check_inside(&outside.join("x.pdf"), &dest).is_err());
+    assert!(confine::check_inside(&dest, &dest).is_err());
+    // A destination that does not exist yet
+    assert!(confine::check_inside(&dest.join("missing/sub/x.pdf"), &dest.join("missing")).is_ok());
+
+    // A directory of the destination linking to outside of it
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+        assert!(confine::check_inside(&dest.join("link/new/x.pdf"), &dest).is_err());
+    }
+}
+
+#[test]
+fn test_sanitize() {
+    use std::path::Path;
+
+    use crate::resolve::SegmentContext;
+    use crate::run;
+    use crate::sanitize::{self, Sanitize};
+    use crate::syntax::Syntax;
+
+    assert_eq!(Sanitize::Posix.apply("a:b?.txt", true), "a:b?.txt");
+    assert_eq!(Sanitize::Windows.apply("a:b?.txt", true), "a_b_.txt");
+    assert_eq!(Sanitize::Windows.apply("Notes. ", false), "Notes");
+    assert_eq!(Sanitize::Windows.apply("...", false), "_");
+    assert_eq!(Sanitize::Windows.apply("con.txt", true), "con_.txt");
+    assert_eq!(Sanitize::Windows.apply("LPT1", false), "LPT1_");
+    assert_eq!(Sanitize::Windows.apply("CONSOLE", false), "CONSOLE");
+    assert_eq!(Sanitize::Portable.apply(" -rf", false), "rf");
+    assert_eq!(Sanitize::Portable.apply("-- -rf", false), "rf");
+    assert_eq!(Sanitize::Portable.apply("--", false), "_");
+    assert_eq!(Sanitize::Windows.apply("-rf", false), "-rf");
+    // Names already sanitized are left as they are
+    assert_eq!(Sanitize::Windows.apply("a|b", false), "a_b");
+    assert_eq!(Sanitize::Windows.apply("a_b", false), "a_b");
+    // Reserved names are renamed before being shortened
+    let reserved = Sanitize::Windows.apply(&format!("con.{}", "x".repeat(300)), true);
+    assert_eq!(reserved.len(), sanitize::MAX_LENGTH);
+    assert!(reserved.starts_with("con_.x"));
+
+    let long = format!("{}.pdf", "é".repeat(200));
+    let truncated = Sanitize::Posix.apply(&long, true);
+    assert!(truncated.len() <= sanitize::MAX_LENGTH);
+    assert!(truncated.ends_with("é.pdf"));
+    assert_eq!(sanitize::truncate("abcdef", 4, false), "abcd");
+    assert_eq!(sanitize::truncate("abcdef.txt", 8, true), "abcd.txt");
+
+    let codes = |code: &str, _: &SegmentContext| match code {
+        "q" => Some("Questions?/Who: me".to_string()),
+        "aux" => Some("aux".to_string()),
+        _ => None,
+    };
+    let syntax = Syntax {
+        separator: "_".into(),
+        detect_extensions: true,
+        sanitize: Sanitize::Windows,
+        ..Syntax::default()
+    };
+    let new_name =
+        run::get_new_name("q_aux_what?.txt", Path::new("/dest"), &codes, None, &syntax).unwrap();
+    assert_eq!(
+        new_name.file,
+        Path::new("/dest/Questions_/Who_ me/aux_/what_.txt")
+    );
+    assert_eq!(new_name.segments, vec!["Questions_/Who_ me", "aux_"]);
+}
+
+#[test]
+fn test_unicode() {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    use crate::case;
+    use crate::conf::lib::DestCase;
+    use crate::resolve::Code;
+    use crate::run;
+    use crate::syntax::Syntax;
+    use crate::unicode::{Ascii, Normalization};
+
+    let nfc = "Fran\u{e7}ais";
+    let nfd = "Franc\u{327}ais";
+    assert_eq!(Normalization::Nfc.apply(nfd), nfc);
+    assert_eq!(Normalization::Nfd.apply(nfc), nfd);
+    assert_eq!(Normalization::None.apply(nfd), nfd);
+
+    assert_eq!(Ascii::Transliterate.apply(nfd, false), "Francais");
+    assert_eq!(Ascii::Transliterate.apply("½", false), "1_2");
+    assert_eq!(
+        Ascii::Slugify.apply("Français 2e année", false),
+        "francais-2e-annee"
+    );
+    assert_eq!(
+        Ascii::Slugify.apply("Été 2024!.tar.GZ", true),
+        "ete-2024.tar.gz"
+    );
+    assert_eq!(Ascii::Slugify.apply("???", false), "_");
+
+    // A code written on macOS is found, and written like the others
+    let mut codes = HashMap::new();
+    codes.insert(nfd.to_string(), Code::from(nfd));
+    let codes = Normalization::Nfc.codes(&codes);
+    assert_eq!(codes[nfc].meaning(), nfc);
+
+    let syntax = Syntax {
+        unicode_normalization: Normalization::Nfc,
+        ..Syntax::default()
+    };
+    let root = TempDir::new("unicode");
+    fs::create_dir_all(root.join(nfd)).unwrap();
+
+    let new_name =
+        run::get_new_name(&format!("{}.cours.txt", nfd), &root, &codes, None, &syntax).unwrap();
+    assert_eq!(new_name.file, root.join(nfc).join("cours.txt"));
+
+    // The directory already there is used, however it's written
+    let new_name = case::normalize(new_name, &root, DestCase::Preserve, Normalization::Nfc);
+    assert_eq!(new_name.dir, root.join(nfd));
+
+    let syntax = Syntax {
+        ascii: Ascii::Transliterate,
+        ..syntax
+    };
+    let new_name = run::get_new_name(
+        &format!("{}.Résumé.txt", nfc),
+        Path::new("/dest"),
+        &codes,
+        None,
+        &syntax,
+    )
+    .unwrap();
+    assert_eq!(new_name.file, Path::new("/dest/Francais/Resume.txt"));
+}
+
+#[test]
+fn test_mime() {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::mime;
+    use crate::run;
+    use crate::syntax::Syntax;
+
+    let mut routes: HashMap<String, PathBuf> = HashMap::new();
+    routes.insert("image/*".into(), "Pictures".into());
+    routes.insert("image/png".into(), "Pictures/Screenshots".into());
+    routes.insert("application/pdf".into(), "Documents/Unsorted".into());
+
+    assert_eq!(
+        mime::route(&routes, "image/png"),
+        Some(Path::new("Pictures/Screenshots"))
+    );
+    assert_eq!(
+        mime::route(&routes, "image/jpeg"),
+        Some(Path::new("Pictures"))
+    );
+    assert_eq!(mime::route(&routes, "video/mp4"), None);
+    routes.insert("*".into(), "Unsorted".into());
+    assert_eq!(
+        mime::route(&routes, "video/mp4"),
+        Some(Path::new("Unsorted"))
+    );
+
+    // The contents tell the type, not the extension
+    let root = TempDir::new("mime");
+    fs::write(root.join("invoice.jpg"), b"%PDF-1.4\n").unwrap();
+    fs::write(root.join("notes.txt"), b"Some notes").unwrap();
+    assert_eq!(
+        mime::sniff(&root.join("invoice.jpg")),
+        Some("application/pdf")
+    );
+    assert_eq!(mime::sniff(&root.join("notes.txt")), None);
+
+    let syntax = Syntax::default();
+    assert!(run::has_codes("mt.notes.txt", &syntax));
+    assert!(!run::has_codes("IMG_0001.jpg", &syntax));
+    assert!(run::has_codes("archive.tar.gz", &syntax));
+    let syntax = Syntax {
+        detect_extensions: true,
+        ..Syntax::default()
+    };
+    assert!(!run::has_codes("archive.tar.gz", &syntax));
+}
+
+#[test]
+fn test_dedupe() {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::conf::lib::Config;
+    use crate::dedupe::{self, Duplicates};
+    use crate::hooks::Runner;
+    use crate::run;
+
+    let root = TempDir::new("dedupe");
+    let dest = root.join("dest");
+    let source = root.join("source");
+    fs::create_dir_all(dest.join("Mathematics")).unwrap();
+    fs::create_dir_all(&source).unwrap();
+
+    fs::write(dest.join("Mathematics/notes.pdf"), "same").unwrap();
+    fs::write(dest.join("other.pdf"), "diff").unwrap();
+    fs::write(dest.join("empty"), "").unwrap();
+    fs::write(source.join("a.pdf"), "same").unwrap();
+    fs::write(source.join("b.pdf"), "same").unwrap();
+    fs::write(source.join("c.pdf"), "other").unwrap();
+    fs::write(source.join("d"), "").unwrap();
+
+    let index = dedupe::Index::new();
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("a.pdf"), &dest.join("a.pdf"))
+            .unwrap()
+            .0,
+        Some(dest.join("Mathematics/notes.pdf"))
+    );
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("c.pdf"), &dest.join("c.pdf"))
+            .unwrap()
+            .0,
+        None
+    );
+    // The hash of the file is given back, so it's read once
+    let (_, hash) = index
+        .claim(&dest, &source.join("b.pdf"), &dest.join("b.pdf"))
+        .unwrap();
+    assert_eq!(hash, dedupe::hash(&source.join("b.pdf")).unwrap());
+    // Empty files aren't duplicates
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("d"), &dest.join("d"))
+            .unwrap()
+            .0,
+        None
+    );
+
+    // A file handled in the same loop is found where it's moved to
+    fs::remove_file(dest.join("Mathematics/notes.pdf")).unwrap();
+    index.refresh();
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("a.pdf"), &dest.join("a.pdf"))
+            .unwrap()
+            .0,
+        None
+    );
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("b.pdf"), &dest.join("b.pdf"))
+            .unwrap()
+            .0,
+        Some(dest.join("a.pdf"))
+    );
+
+    // The destination is kept from a loop to the next, and only its
+    // directories modified since are listed again
+    index.refresh();
+    fs::create_dir_all(dest.join("Physics")).unwrap();
+    fs::write(dest.join("Physics/c.pdf"), "other").unwrap();
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("c.pdf"), &dest.join("c.pdf"))
+            .unwrap()
+            .0,
+        Some(dest.join("Physics/c.pdf"))
+    );
+    let modified = fs::metadata(dest.join("Mathematics"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    fs::write(dest.join("Mathematics/f.pdf"), "fresh").unwrap();
+    fs::write(source.join("f.pdf"), "fresh").unwrap();
+    fs::File::open(dest.join("Mathematics"))
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    index.refresh();
+    assert_eq!(
+        index
+            .claim(&dest, &source.join("f.pdf"), &dest.join("f.pdf"))
+            .unwrap()
+            .0,
+        None
+    );
+    fs::remove_file(dest.join("Mathematics/f.pdf")).unwrap();
+    fs::remove_dir_all(dest.join("Physics")).unwrap();
+
+    fs::copy(source.join("a.pdf"), dest.join("Mathematics/notes.pdf")).unwrap();
+    fs::copy(source.join("a.pdf"), dest.join("copy.pdf")).unwrap();
+    fs::write(dest.join("empty2"), "").unwrap();
+    #[cfg(unix)]
+    fs::hard_link(dest.join("copy.pdf"), dest.join("link.pdf")).unwrap();
+    assert_eq!(
+        dedupe::find(&dest, &[]),
+        vec![vec![
+            dest.join("Mathematics/notes.pdf"),
+            dest.join("copy.pdf")
+        ]]
+    );
+
+    // Files handled under each policy
+    let inbox = root.join("inbox");
+    let library = root.join("library");
+    fs::create_dir_all(&inbox).unwrap();
+    fs::create_dir_all(library.join("Mathematics")).unwrap();
+    fs::write(library.join("Mathematics/notes.pdf"), "notes").unwrap();
+
+    let runner = Runner::new();
+    let index = dedupe::Index::new();
+    let handle = |name: &str, duplicates| {
+        let config = Config {
+            dest: library.clone(),
+            dirs: HashSet::from([inbox.clone()]),
+            codes: serde_yaml::from_str("mt: Mathematics").unwrap(),
+            duplicates,
+            resolve_symlinks: true,
+            ..Config::default()
+        };
+        run::handle(&inbox.join(name), &config, &runner, None, &index, None)
+    };
+
+    fs::write(inbox.join("mt.a.pdf"), "notes").unwrap();
+    assert!(handle("mt.a.pdf", Duplicates::Keep));
+    assert_eq!(
+        fs::read_to_string(library.join("Mathematics/a.pdf")).unwrap(),
+        "notes"
+    );
+
+    fs::write(inbox.join("mt.b.pdf"), "notes").unwrap();
+    assert!(handle("mt.b.pdf", Duplicates::Hardlink));
+    assert!(!inbox.join("mt.b.pdf").exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |name: &str| fs::metadata(library.join(name)).unwrap().ino();
+        assert_eq!(inode("Mathematics/b.pdf"), inode("Mathematics/notes.pdf"));
+    }
+
+    fs::write(inbox.join("mt.c.pdf"), "notes").unwrap();
+    assert!(handle("mt.c.pdf", Duplicates::Delete));
+    assert!(!inbox.join("mt.c.pdf").exists());
+    assert!(!library.join("Mathematics/c.pdf").exists());
+
+    // A file claimed by one which wasn't moved isn't a reason to delete
+    fs::write(inbox.join("mt.d.pdf"), "ghost").unwrap();
+    let ghost = library.join("ghost.pdf");
+    assert_eq!(
+        index
+            .claim(&library, &inbox.join("mt.d.pdf"), &ghost)
+            .unwrap()
+            .0,
+        None
+    );
+    assert!(!handle("mt.d.pdf", Duplicates::Delete));
+    assert!(inbox.join("mt.d.pdf").exists());
+    index.release(&library, &ghost);
+    assert!(handle("mt.d.pdf", Duplicates::Delete));
+    assert!(library.join("Mathematics/d.pdf").exists());
+
+    // Symlinks to files outside of the watching directories aren't followed
+    #[cfg(unix)]
+    {
+        fs::write(root.join("outside.pdf"), "notes").unwrap();
+        std::os::unix::fs::symlink(root.join("outside.pdf"), inbox.join("mt.e.pdf")).unwrap();
+        assert!(!handle("mt.e.pdf", Duplicates::Delete));
+        assert!(root.join("outside.pdf").exists());
+    }
+}
+
+#[test]
+fn test_database() {
+    use std::fs;
+
+    use crate::database::{self, Database, Move, Query};
+    use crate::dedupe;
+    use crate::run;
+
+    let root = TempDir::new("database");
+    fs::create_dir_all(root.join("Mathematics/Assignments")).unwrap();
+    fs::create_dir_all(root.join("Documents")).unwrap();
+    let database = Database::open(&root.join("moves.sqlite")).unwrap();
+
+    let integrals = Move {
+        name: "MT.asg.integrals.pdf".into(),
+        source_dir: root.join("inbox"),
+        codes: vec!["MT".into(), "asg".into()],
+        segments: vec!["Mathematics".into(), "Assignments".into()],
+        target: root.join("Mathematics/Assignments/integrals.pdf"),
+        created: Some(1_000),
+        modified: Some(2_000),
+        moved: 86_400 * 10,
+        hash: Some("af13".into()),
+    };
+    let invoice = Move {
+        name: "invoice.pdf".into(),
+        source_dir: root.join("inbox"),
+        codes: vec![],
+        segments: vec!["Documents".into()],
+        target: root.join("Documents/invoice.pdf"),
+        created: None,
+        modified: None,
+        moved: 86_400 * 20,
+        hash: None,
+    };
+    for moved in [&integrals, &invoice] {
+        fs::write(&moved.target, "").unwrap();
+        database.record(moved).unwrap();
+    }
+
+    let search = |query: Query| -> Vec<String> {
+        database
+            .search(&query)
+            .unwrap()
+            .into_iter()
+            .map(|moved| moved.name)
+            .collect()
+    };
+    assert_eq!(search(Query::default()).len(), 2);
+    assert_eq!(
+        search(Query {
+            code: Some("mt".into()),
+            ..Query::default()
+        }),
+        vec!["MT.asg.integrals.pdf"]
+    );
+    assert_eq!(
+        search(Query {
+            meaning: Some("docu".into()),
+            ..Query::default()
+        }),
+        vec!["invoice.pdf"]
+    );
+    assert_eq!(
+        search(Query {
+            name: Some("INTEGRAL".into()),
+            since: Some(86_400 * 10),
+            until: Some(86_400 * 11),
+            ..Query::default()
+        }),
+        vec!["MT.asg.integrals.pdf"]
+    );
+    assert!(search(Query {
+        since: Some(86_400 * 21),
+        ..Query::default()
+    })
+    .is_empty());
+    assert_eq!(database.search(&Query::default()).unwrap()[0], integrals);
+
+    // A file moved where another one was replaces it
+    database
+        .record(&Move {
+            name: "mt.asg.integrals.pdf".into(),
+            moved: 86_400 * 30,
+            ..integrals.clone()
+        })
+        .unwrap();
+    assert_eq!(
+        search(Query::default()),
+        vec!["invoice.pdf", "mt.asg.integrals.pdf"]
+    );
+
+    // Searching leaves out the files which disappeared, without forgetting them
+    fs::remove_file(&invoice.target).unwrap();
+    database::report(&root.join("moves.sqlite"), &Query::default()).unwrap();
+    assert_eq!(search(Query::default()).len(), 2);
+    // The daemon forgets them, unless the destination isn't there
+    run::sync_database(&database, &root.join("unmounted"));
+    assert_eq!(search(Query::default()).len(), 2);
+    run::sync_database(&database, &root);
+    assert_eq!(search(Query::default()), vec!["mt.asg.integrals.pdf"]);
+    assert_eq!(database.sync().unwrap(), 0);
+
+    // The database is never a duplicate
+    let files = database::files(&root.join("moves.sqlite"));
+    assert_eq!(files[1], root.join("moves.sqlite-journal"));
+    fs::copy(root.join("moves.sqlite"), root.join("copy.sqlite")).unwrap();
+    assert_eq!(dedupe::find(&root, &[]).len(), 1);
+    assert!(dedupe::find(&root, &files).is_empty());
 }
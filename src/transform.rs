@@ -0,0 +1,77 @@
+// Separates a function's name, its arguments and the value it applies to, like `{pad:3:7}`
+pub const ARGUMENT_SEPARATOR: char = ':';
+
+// Functions applied to a variable's value, like `{upper:fr}`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transform {
+    Upper,
+    Lower,
+    Title,
+    Trim,
+    // Keeps at most that many characters
+    Truncate(usize),
+    // Adds zeros in front of the value until it's that long
+    Pad(usize),
+    Replace(String, String),
+}
+
+impl Transform {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Self::Upper => value.to_uppercase(),
+            Self::Lower => value.to_lowercase(),
+            Self::Title => title(value),
+            Self::Trim => value.trim().to_owned(),
+            Self::Truncate(length) => value.chars().take(*length).collect(),
+            Self::Pad(length) => {
+                let missing = length.saturating_sub(value.chars().count());
+                "0".repeat(missing) + value
+            }
+            Self::Replace(from, _) if from.is_empty() => value.to_owned(),
+            Self::Replace(from, to) => value.replace(from.as_str(), to),
+        }
+    }
+}
+
+fn title(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut word_start = true;
+
+    for c in value.chars() {
+        if word_start {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+        word_start = c.is_whitespace() || c == '-';
+    }
+
+    result
+}
+
+// Returns the function a code calls, and the code it's applied to. Codes
+// which aren't function calls give None
+pub fn parse(code: &str) -> Option<(Transform, &str)> {
+    let (name, rest) = code.split_once(ARGUMENT_SEPARATOR)?;
+
+    let arguments = match name {
+        "upper" | "lower" | "title" | "trim" => 0,
+        "truncate" | "pad" => 1,
+        "replace" => 2,
+        _ => return None,
+    };
+
+    let mut parts = rest.splitn(arguments + 1, ARGUMENT_SEPARATOR);
+    let mut argument = || parts.next();
+    let transform = match name {
+        "upper" => Transform::Upper,
+        "lower" => Transform::Lower,
+        "title" => Transform::Title,
+        "trim" => Transform::Trim,
+        "truncate" => Transform::Truncate(argument()?.parse().ok()?),
+        "pad" => Transform::Pad(argument()?.parse().ok()?),
+        _ => Transform::Replace(argument()?.to_owned(), argument()?.to_owned()),
+    };
+
+    Some((transform, parts.next()?))
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc_quick, is_nfd_quick, IsNormalized, UnicodeNormalization};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::resolve::Code;
+
+// How accented letters are written. macOS writes `ç` as `c` followed by a
+// combining cedilla (NFD), most other systems as one character (NFC)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+    None,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            "none" => Ok(Self::None),
+            _ => Err(format!("invalid unicode normalization `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Nfc => write!(f, "nfc"),
+            Self::Nfd => write!(f, "nfd"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+impl Normalization {
+    pub fn apply(self, s: &str) -> Cow<'_, str> {
+        match self {
+            Self::Nfc if is_nfc_quick(s.chars()) != IsNormalized::Yes => {
+                Cow::Owned(s.nfc().collect())
+            }
+            Self::Nfd if is_nfd_quick(s.chars()) != IsNormalized::Yes => {
+                Cow::Owned(s.nfd().collect())
+            }
+            _ => Cow::Borrowed(s),
+        }
+    }
+
+    // The codes, their aliases and their meanings, normalized
+    pub fn codes(self, codes: &HashMap<String, Code>) -> HashMap<String, Code> {
+        let mut keys: Vec<&String> = codes.keys().collect();
+        keys.sort();
+
+        keys.into_iter()
+            .map(|key| {
+                let code = match &codes[key] {
+                    Code::Meaning(meaning) => Code::Meaning(self.apply(meaning).into_owned()),
+                    Code::Entry {
+                        name,
+                        aliases,
+                        codes,
+                    } => Code::Entry {
+                        name: self.apply(name).into_owned(),
+                        aliases: aliases
+                            .iter()
+                            .map(|alias| self.apply(alias).into_owned())
+                            .collect(),
+                        codes: self.codes(codes),
+                    },
+                };
+                (self.apply(key).into_owned(), code)
+            })
+            .collect()
+    }
+}
+
+// Whether the directories and files created are written with ASCII only
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ascii {
+    // Names are kept as they are
+    None,
+    // `Français` becomes `Francais`
+    Transliterate,
+    // `Français 2e année` becomes `francais-2e-annee`
+    Slugify,
+}
+
+impl FromStr for Ascii {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "transliterate" => Ok(Self::Transliterate),
+            "slugify" => Ok(Self::Slugify),
+            _ => Err(format!("invalid ascii mode `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Ascii {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Transliterate => write!(f, "transliterate"),
+            Self::Slugify => write!(f, "slugify"),
+        }
+    }
+}
+
+// Characters without ASCII equivalent are replaced by this
+const UNKNOWN: &str = "_";
+
+// Transliterations can hold slashes, like `½` giving `1/2`
+fn transliterate(name: &str) -> String {
+    deunicode::deunicode_with_tofu(name, UNKNOWN).replace(['/', '\\'], UNKNOWN)
+}
+
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in transliterate(name).chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        UNKNOWN.to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+impl Ascii {
+    // Makes a directory name, or a file name if `is_file`, ASCII only. Files
+    // keep their dots, so that `notes.tar.gz` stays one
+    pub fn apply(self, name: &str, is_file: bool) -> String {
+        match self {
+            Self::None => name.to_owned(),
+            Self::Transliterate => transliterate(name),
+            Self::Slugify if is_file => {
+                let pieces: Vec<String> = name
+                    .split('.')
+                    .map(|piece| {
+                        if piece.is_empty() {
+                            String::new()
+                        } else {
+                            slugify(piece)
+                        }
+                    })
+                    .collect();
+                pieces.join(".")
+            }
+            Self::Slugify => slugify(name),
+        }
+    }
+}
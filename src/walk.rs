@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path;
+
+use crate::conf::lib::SymlinkPolicy;
+
+// Hidden files and backup files (`*~`, `*.bak`, `#*#`) are never touched
+#[inline]
+fn is_ignored(name: &str) -> bool {
+    name.starts_with('.')
+        || name.ends_with('~')
+        || name.ends_with(".bak")
+        || (name.starts_with('#') && name.ends_with('#'))
+}
+
+// Lists the files inside `dir`, going at most `max_depth` directories deep
+pub fn files(
+    dir: &path::Path,
+    max_depth: Option<usize>,
+    symlinks: SymlinkPolicy,
+) -> Vec<path::PathBuf> {
+    let mut result = vec![];
+    let mut visited = HashSet::new();
+
+    if symlinks == SymlinkPolicy::All {
+        if let Ok(canonical) = fs::canonicalize(dir) {
+            visited.insert(canonical);
+        }
+    }
+
+    walk(dir, 0, max_depth, symlinks, &mut visited, &mut result);
+
+    result
+}
+
+// The directories and files directly inside `dir`, without following
+// symlinks
+pub fn list(dir: &path::Path) -> (Vec<path::PathBuf>, Vec<path::PathBuf>) {
+    let (mut dirs, mut files) = (vec![], vec![]);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Can't read directory {:?} : {}", dir, e);
+            return (dirs, files);
+        }
+    };
+
+    for entry in entries.flatten() {
+        if entry.file_name().to_str().is_none_or(is_ignored) {
+            continue;
+        }
+
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+            Ok(file_type) if file_type.is_file() => files.push(entry.path()),
+            _ => (),
+        }
+    }
+
+    (dirs, files)
+}
+
+fn walk(
+    dir: &path::Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    symlinks: SymlinkPolicy,
+    visited: &mut HashSet<path::PathBuf>,
+    result: &mut Vec<path::PathBuf>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Can't read directory {:?} : {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        match entry.file_name().to_str() {
+            Some(name) if is_ignored(name) => continue,
+            Some(_) => (),
+            None => {
+                log::warn!("Path {:?} isn't valid UTF-8. Not using it", path);
+                continue;
+            }
+        }
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                log::warn!("Can't get type of {:?} : {}", path, e);
+                continue;
+            }
+        };
+
+        let is_symlink = file_type.is_symlink();
+        let (is_dir, is_file) = if is_symlink {
+            if symlinks == SymlinkPolicy::Skip {
+                continue;
+            }
+
+            match fs::metadata(&path) {
+                Ok(metadata) => (metadata.is_dir(), metadata.is_file()),
+                Err(_) => {
+                    log::debug!("Symlink {:?} is broken, ignoring it", path);
+                    continue;
+                }
+            }
+        } else {
+            (file_type.is_dir(), file_type.is_file())
+        };
+
+        if is_dir {
+            if is_symlink && symlinks != SymlinkPolicy::All {
+                continue;
+            }
+
+            if max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                continue;
+            }
+
+            // Only possible if symlinks to directories are followed
+            if symlinks == SymlinkPolicy::All {
+                match fs::canonicalize(&path) {
+                    Ok(canonical) => {
+                        if !visited.insert(canonical) {
+                            log::debug!(
+                                "Directory {:?} was already visited, not following it",
+                                path
+                            );
+                            continue;
+                        }
+                    }
+                    Err(_) => continue,
+                }
+            }
+
+            walk(&path, depth + 1, max_depth, symlinks, visited, result);
+        } else if is_file {
+            result.push(path);
+        }
+    }
+}